@@ -0,0 +1,198 @@
+//! Measures routing an object to many subscribers: with the string matching
+//! subscriptions had before they were compiled, matching each client's
+//! subscription as given with `routing_decision`, matching subscriptions
+//! compiled once, and looking the subscribers up in an index of the
+//! subscriptions.
+//!
+//! Run with `cargo bench --bench routing`.
+
+extern crate object_system;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use object_system::BusinessObject;
+use object_system::subscription::{routing_decision, BusinessSubscription, RoutingKey, SubscriptionIndex,
+                                  SubscriptionMatcher};
+
+
+const ROUNDS: u32 = 200;
+
+
+type Decide = fn(Option<Vec<&str>>, Option<&str>, Option<&str>, &BusinessSubscription) -> bool;
+
+
+/// The matching `routing_decision` did before subscriptions were compiled,
+/// as the reference: every rule is split up again for every object.
+mod baseline {
+    use object_system::subscription::BusinessSubscription;
+
+    fn match_hierarchical(matcher: &str, matchable: &str) -> bool {
+        let matcher_parts: Vec<&str> = matcher.split('/').collect();
+        let matchable_parts: Vec<&str> = matchable.split('/').collect();
+
+        for (index, matcher_part) in matcher_parts.iter().enumerate() {
+            if matcher_part == &"*" {
+                return true;
+            }
+
+            if index >= matchable_parts.len() {
+                return false;
+            }
+
+            if matcher_part != &matchable_parts[index] {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn subscription_vec_to_str_vec(subscription_vec: &[BusinessSubscription]) -> Option<Vec<&str>> {
+        let mut result: Vec<&str> = Vec::new();
+
+        for item in subscription_vec.iter() {
+            match *item {
+                BusinessSubscription::String(ref s) => result.push(s),
+                _ => return None
+            }
+        }
+
+        Some(result)
+    }
+
+    fn routing_decision_aux(natures: Option<Vec<&str>>, event: Option<&str>, payload_type: Option<&str>,
+                            subscription_rules: &[BusinessSubscription]) -> bool {
+        let rules = match subscription_vec_to_str_vec(subscription_rules) {
+            Some(rules) => rules,
+            None => return false
+        };
+
+        let mut pass = false;
+
+        for mut rule in rules {
+            let is_negative_rule = rule.starts_with('!');
+            if is_negative_rule {
+                rule = &rule[1..];
+            }
+
+            if let Some(nature_rule) = rule.strip_prefix('#') {
+                if let Some(ref nature_list) = natures {
+                    if nature_list.iter().any(|nature| match_hierarchical(nature_rule, nature)) {
+                        pass = !is_negative_rule;
+                    }
+                }
+            } else if let Some(event_rule) = rule.strip_prefix('@') {
+                if let Some(event) = event {
+                    if match_hierarchical(event_rule, event) {
+                        pass = !is_negative_rule;
+                    }
+                }
+            } else if rule == "*" || match payload_type { Some(payload_type) => match_hierarchical(rule, payload_type),
+                                                          None => false } {
+                pass = !is_negative_rule;
+            }
+        }
+
+        pass
+    }
+
+    pub fn routing_decision(natures: Option<Vec<&str>>, event: Option<&str>, payload_type: Option<&str>,
+                            subscription: &BusinessSubscription) -> bool {
+        // Remove trailing extra qualifiers for type for matching purposes
+        let payload_type = payload_type.map(|val| val.split(';').next().unwrap().trim());
+
+        match *subscription {
+            BusinessSubscription::List(ref rule_list) =>
+                routing_decision_aux(natures, event, payload_type, rule_list),
+            _ => false
+        }
+    }
+}
+
+
+fn subscription(client: usize) -> BusinessSubscription {
+    let rules = vec![
+        format!("@service-{}/*", client),
+        format!("#nature-{}", client % 50),
+        "!@routing/*".to_string(),
+        format!("text/x-{}", client % 10),
+        "!#internal/debug".to_string(),
+        format!("@sensors/{}/temperature", client % 100),
+    ];
+
+    BusinessSubscription::List(rules.into_iter().map(BusinessSubscription::String).collect())
+}
+
+
+fn objects() -> Vec<BusinessObject> {
+    vec![
+        BusinessObject::builder().event("sensors/42/temperature").natures(vec!["nature-7"]).build(),
+        BusinessObject::builder().event("routing/announcement").build(),
+        BusinessObject::builder().event("service-3/status").payload_type("text/x-3; charset=utf-8").build(),
+    ]
+}
+
+
+fn time<F: FnMut() -> usize>(mut route: F) -> (Duration, usize) {
+    let start = Instant::now();
+    let mut delivered = 0;
+
+    for _ in 0..ROUNDS {
+        delivered += black_box(route());
+    }
+
+    (start.elapsed() / ROUNDS, delivered / ROUNDS as usize)
+}
+
+
+fn main() {
+    let objects = objects();
+
+    for &clients in &[10, 100, 1000, 10000] {
+        let subscriptions: Vec<BusinessSubscription> = (0..clients).map(subscription).collect();
+        let matchers: Vec<SubscriptionMatcher> = subscriptions.iter().map(SubscriptionMatcher::compile).collect();
+        let mut index = SubscriptionIndex::new();
+        for (client, subscription) in subscriptions.iter().enumerate() {
+            index.insert(client, subscription);
+        }
+
+        let each_subscription = |decide: Decide| {
+            let mut delivered = 0;
+            for object in &objects {
+                let natures = object.natures();
+                let event = object.event.as_ref().map(|event| event.as_ref());
+                let payload_type = object._type.as_ref().map(|t| t.as_ref());
+                for subscription in &subscriptions {
+                    if decide(Some(natures.clone()), event, payload_type, subscription) {
+                        delivered += 1;
+                    }
+                }
+            }
+            delivered
+        };
+
+        let (reference, expected) = time(|| each_subscription(baseline::routing_decision));
+        let (uncompiled, decided) = time(|| each_subscription(routing_decision));
+
+        let (compiled, delivered) = time(|| {
+            let mut delivered = 0;
+            for object in &objects {
+                let key = RoutingKey::from_object(object);
+                delivered += matchers.iter().filter(|matcher| matcher.matches(&key)).count();
+            }
+            delivered
+        });
+
+        let (indexed, looked_up) = time(|| {
+            objects.iter().map(|object| index.lookup(&RoutingKey::from_object(object)).len()).sum()
+        });
+
+        assert_eq!(expected, decided);
+        assert_eq!(expected, delivered);
+        assert_eq!(expected, looked_up);
+        println!("{:>6} clients: baseline {:>12?}/round, routing_decision {:>12?}/round, \
+                  compiled {:>12?}/round, indexed {:>12?}/round ({} deliveries)",
+                 clients, reference, uncompiled, compiled, indexed, delivered);
+    }
+}
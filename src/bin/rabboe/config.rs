@@ -0,0 +1,460 @@
+//! Server options, from the command line and an optional JSON config file.
+//!
+//! Every option has the same name on the command line (`--max-clients 256`)
+//! and in the config file (`"max-clients": 256`). Options on the command
+//! line override the ones in the file; listen addresses given on the command
+//! line replace the file's instead of adding to them.
+
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+
+use rustc_serialize::json::Json;
+
+use object_system::io::{DecoderLimits, READ_BUF_SIZE};
+use object_system::subscription::{self, BusinessSubscription};
+
+use net::ListenAddr;
+
+
+pub const USAGE: &str = "Usage: rabboe [options]
+
+Options:
+  -c, --config FILE            read options from a JSON file
+  -l, --listen ADDR            listen on host:port or unix:PATH; may be repeated
+      --unix-socket-mode MODE  give Unix sockets the octal permissions MODE,
+                               e.g. 660; left to the umask by default
+      --max-clients N          accept at most N clients at a time, turning
+                               away the rest; unbounded by default
+      --read-buffer-size BYTES read at most BYTES from a client at a time
+      --max-header-len BYTES   disconnect clients sending longer headers
+      --max-payload-size BYTES disconnect clients sending larger payloads
+      --max-buffered BYTES     disconnect clients making us buffer more
+      --max-queued-objects N   queue at most N objects for a client
+      --max-queued-bytes BYTES queue at most BYTES for a client
+      --slow-consumer-policy POLICY
+                               what to do when a client's queue is full:
+                               disconnect, drop-oldest, drop-newest or
+                               drop-natures:NATURE,... to drop only objects
+                               with one of the natures and disconnect when
+                               that isn't enough; disconnect by default
+      --keepalive-interval SECONDS
+                               ping clients idle for this long; off by default
+      --keepalive-timeout SECONDS
+                               disconnect clients not answering a ping with
+                               pong in this time; 30 by default
+      --default-subscription RULES
+                               subscribe clients that start sending without
+                               routing/subscribe, or with a bad one, to these
+                               comma-separated rules, which may be none at
+                               all; by default such clients are disconnected
+      --log-level SPEC         log level or filters, e.g. info or rabboe=debug;
+                               RUST_LOG overrides this when set
+  -h, --help                   print this help
+
+Limits given as `none` are unbounded.";
+
+
+const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:7890";
+
+
+/// What to do with an object that doesn't fit in a client's send queue.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SlowConsumerPolicy {
+    Disconnect,
+    /// Drop queued objects, oldest first, to make room.
+    DropOldest,
+    /// Drop the object that doesn't fit.
+    DropNewest,
+    /// Drop objects with any of these natures, oldest first; disconnect if
+    /// that doesn't make room.
+    DropNatures(Vec<String>)
+}
+
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SlowConsumerPolicy, String> {
+        match s {
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            "drop-oldest" => Ok(SlowConsumerPolicy::DropOldest),
+            "drop-newest" => Ok(SlowConsumerPolicy::DropNewest),
+            _ => match s.strip_prefix("drop-natures:") {
+                Some(natures) if !natures.is_empty() =>
+                    Ok(SlowConsumerPolicy::DropNatures(natures.split(',').map(String::from).collect())),
+                _ => Err(format!("Unknown slow consumer policy {:?}", s))
+            }
+        }
+    }
+}
+
+
+/// Bounds on a client's send queue.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueLimits {
+    pub max_objects: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub policy: SlowConsumerPolicy
+}
+
+
+/// When to ping idle clients and how long to wait for them to answer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keepalive {
+    /// Seconds of silence before a client is pinged; `None`, the default,
+    /// turns pinging off.
+    pub interval: Option<u64>,
+    /// Seconds to wait for the pong.
+    pub timeout: u64
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub listen: Vec<ListenAddr>,
+    /// Permissions of the Unix socket files; `None` leaves them to the
+    /// umask.
+    pub unix_socket_mode: Option<u32>,
+    pub max_clients: Option<usize>,
+    pub read_buffer_size: usize,
+    pub limits: DecoderLimits,
+    pub queue: QueueLimits,
+    pub keepalive: Keepalive,
+    /// The subscription of clients that don't subscribe themselves; `None`
+    /// disconnects them instead.
+    pub default_subscription: Option<BusinessSubscription>,
+    pub log_level: Option<String>
+}
+
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: Vec::new(),
+            unix_socket_mode: None,
+            max_clients: None,
+            read_buffer_size: READ_BUF_SIZE,
+            limits: DecoderLimits {
+                max_header_len: Some(64 * 1024),
+                max_payload_size: Some(256 * 1024 * 1024),
+                max_buffered: Some(256 * 1024 * 1024 + 64 * 1024 + 1)
+            },
+            queue: QueueLimits {
+                max_objects: None,
+                max_bytes: Some(512 * 1024 * 1024),
+                policy: SlowConsumerPolicy::Disconnect
+            },
+            keepalive: Keepalive {
+                interval: None,
+                timeout: 30
+            },
+            default_subscription: None,
+            log_level: None
+        }
+    }
+}
+
+
+/// What the command line asks for.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Box<Config>),
+    Help
+}
+
+
+/// Parses the command line, without the program name, reading the config
+/// file it names.
+pub fn parse_args<I: IntoIterator<Item=String>>(args: I) -> Result<Command, String> {
+    let mut config_path = None;
+    let mut options = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (name, inline_value) = match arg.find('=') {
+            Some(pos) if arg.starts_with("--") => (arg[.. pos].to_string(), Some(arg[pos + 1 ..].to_string())),
+            _ => (arg.clone(), None)
+        };
+
+        let key = match name.as_ref() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-c" | "--config" => "config",
+            "-l" | "--listen" => "listen",
+            _ if name.starts_with("--") => &name[2 ..],
+            _ => return Err(format!("Unexpected argument {:?}", arg))
+        }.to_string();
+
+        let value = match inline_value.or_else(|| args.next()) {
+            Some(value) => value,
+            None => return Err(format!("Missing value for {}", name))
+        };
+
+        if key == "config" {
+            config_path = Some(value);
+        } else {
+            options.push((key, value));
+        }
+    }
+
+    let mut config = match config_path {
+        Some(path) => Config::from_file(&path)?,
+        None => Config::default()
+    };
+
+    if options.iter().any(|(key, _)| key == "listen") {
+        config.listen.clear();
+    }
+    for (key, value) in options {
+        config.set(&key, &value)?;
+    }
+
+    Ok(Command::Run(Box::new(config.with_default_listen())))
+}
+
+
+impl Config {
+    pub fn from_file(path: &str) -> Result<Config, String> {
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| format!("Couldn't read config file {}: {}", path, e))?;
+
+        Config::from_json_str(&contents)
+            .map_err(|e| format!("Bad config file {}: {}", path, e))
+    }
+
+    fn from_json_str(contents: &str) -> Result<Config, String> {
+        let json = Json::from_str(contents).map_err(|e| e.to_string())?;
+        let object = match json.as_object() {
+            Some(object) => object,
+            None => return Err("Expected a JSON object".to_string())
+        };
+
+        let mut config = Config::default();
+        for (key, value) in object.iter() {
+            match (key.as_ref(), value) {
+                ("listen", Json::Array(addrs)) => {
+                    for addr in addrs {
+                        match addr.as_string() {
+                            Some(addr) => config.set(key, addr)?,
+                            None => return Err(format!("Expected strings in {}", key))
+                        }
+                    }
+                },
+                ("listen", Json::String(addr)) => config.set(key, addr)?,
+                ("listen", _) => return Err(format!("Expected a string or a list of strings in {}", key)),
+                ("default-subscription", Json::Null) => config.default_subscription = None,
+                ("default-subscription", _) => {
+                    let rules = subscription::parse_subscription(value)
+                        .map_err(|e| format!("Bad subscription in {}: {:?}", key, e))?;
+                    config.default_subscription = Some(rules);
+                },
+                (_, Json::String(value)) => config.set(key, value)?,
+                (_, Json::Null) => config.set(key, "none")?,
+                (_, Json::I64(_)) | (_, Json::U64(_)) => config.set(key, &value.to_string())?,
+                _ => return Err(format!("Unexpected value for {}: {}", key, value))
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Sets the option `key` from its textual `value`. Listen addresses are
+    /// added to the ones already set.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "listen" => self.listen.push(ListenAddr::from_str(value)?),
+            "unix-socket-mode" => self.unix_socket_mode = match value {
+                "none" => None,
+                _ => Some(parse_mode(key, value)?)
+            },
+            "max-clients" => self.max_clients = match value {
+                "none" => None,
+                _ => Some(parse_count(key, value)?)
+            },
+            "read-buffer-size" => self.read_buffer_size = parse_count(key, value)?,
+            "max-header-len" => self.limits.max_header_len = parse_limit(key, value)?,
+            "max-payload-size" => self.limits.max_payload_size = parse_limit(key, value)?,
+            "max-buffered" => self.limits.max_buffered = parse_limit(key, value)?,
+            "max-queued-objects" => self.queue.max_objects = parse_limit(key, value)?,
+            "max-queued-bytes" => self.queue.max_bytes = parse_limit(key, value)?,
+            "slow-consumer-policy" => self.queue.policy = SlowConsumerPolicy::from_str(value)?,
+            "keepalive-interval" => self.keepalive.interval = match value {
+                "none" => None,
+                _ => Some(parse_count(key, value)? as u64)
+            },
+            "keepalive-timeout" => self.keepalive.timeout = parse_count(key, value)? as u64,
+            "default-subscription" => self.default_subscription = match value {
+                "none" => None,
+                _ => {
+                    let rules: Vec<Json> = value.split(',')
+                        .map(|rule| rule.trim())
+                        .filter(|rule| !rule.is_empty())
+                        .map(|rule| Json::String(rule.to_string()))
+                        .collect();
+                    let rules = subscription::parse_subscription(&Json::Array(rules))
+                        .map_err(|e| format!("Bad subscription in {}: {:?}", key, e))?;
+                    Some(rules)
+                }
+            },
+            "log-level" => self.log_level = Some(value.to_string()),
+            _ => return Err(format!("Unknown option {}", key))
+        }
+
+        Ok(())
+    }
+
+    fn with_default_listen(mut self) -> Config {
+        if self.listen.is_empty() {
+            self.listen.push(ListenAddr::from_str(DEFAULT_LISTEN_ADDR).unwrap());
+        }
+        self
+    }
+}
+
+
+/// Parses a positive number.
+fn parse_count(key: &str, value: &str) -> Result<usize, String> {
+    match usize::from_str(value) {
+        Ok(0) | Err(_) => Err(format!("Expected a positive number for {}, got {:?}", key, value)),
+        Ok(n) => Ok(n)
+    }
+}
+
+
+/// Parses octal file permissions such as `660`.
+fn parse_mode(key: &str, value: &str) -> Result<u32, String> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("Expected octal permissions for {}, got {:?}", key, value))
+    }
+}
+
+
+/// Parses a number of bytes, or `none` for no limit.
+fn parse_limit(key: &str, value: &str) -> Result<Option<usize>, String> {
+    if value == "none" {
+        return Ok(None);
+    }
+    usize::from_str(value)
+        .map(Some)
+        .map_err(|_| format!("Expected a number or none for {}, got {:?}", key, value))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use net::ListenAddr;
+
+    use object_system::subscription::BusinessSubscription;
+
+    use super::{parse_args, Command, Config, SlowConsumerPolicy};
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn run_config(args: &[&str]) -> Config {
+        match parse(args) {
+            Ok(Command::Run(config)) => *config,
+            other => panic!("Unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn should_default_to_local_tcp_port() {
+        let config = run_config(&[]);
+        assert_eq!(vec![ListenAddr::from_str("127.0.0.1:7890").unwrap()], config.listen);
+        assert_eq!(Config::default().limits, config.limits);
+    }
+
+    #[test]
+    fn should_parse_options() {
+        let config = run_config(&["-l", "0.0.0.0:7891", "--listen=unix:/tmp/rabboe.sock",
+                                  "--max-clients", "10", "--max-payload-size", "none",
+                                  "--max-header-len=1024", "--log-level", "debug"]);
+
+        assert_eq!(vec![ListenAddr::from_str("0.0.0.0:7891").unwrap(),
+                        ListenAddr::from_str("unix:/tmp/rabboe.sock").unwrap()],
+                   config.listen);
+        assert_eq!(Some(10), config.max_clients);
+        assert_eq!(None, config.keepalive.interval);
+        assert_eq!(None, config.limits.max_payload_size);
+        assert_eq!(Some(1024), config.limits.max_header_len);
+        assert_eq!(Some("debug".to_string()), config.log_level);
+    }
+
+    #[test]
+    fn should_parse_unix_socket_mode() {
+        assert_eq!(None, run_config(&[]).unix_socket_mode);
+        assert_eq!(Some(0o660), run_config(&["--unix-socket-mode", "660"]).unix_socket_mode);
+        assert_eq!(Some(0o600), Config::from_json_str(r#"{"unix-socket-mode": "0600"}"#).unwrap().unix_socket_mode);
+        assert!(parse(&["--unix-socket-mode", "680"]).is_err());
+        assert!(parse(&["--unix-socket-mode", "17777"]).is_err());
+    }
+
+    #[test]
+    fn should_reject_bad_options() {
+        assert!(parse(&["--max-clients", "0"]).is_err());
+        assert!(parse(&["--max-clients"]).is_err());
+        assert!(parse(&["--no-such-option", "1"]).is_err());
+        assert!(parse(&["7890"]).is_err());
+        assert_eq!(Ok(Command::Help), parse(&["--max-clients", "1", "-h"]));
+    }
+
+    #[test]
+    fn should_parse_slow_consumer_policies() {
+        assert_eq!(Ok(SlowConsumerPolicy::DropOldest), SlowConsumerPolicy::from_str("drop-oldest"));
+        assert_eq!(Ok(SlowConsumerPolicy::DropNatures(vec!["volatile".to_string(), "debug".to_string()])),
+                   SlowConsumerPolicy::from_str("drop-natures:volatile,debug"));
+        assert!(SlowConsumerPolicy::from_str("drop-natures:").is_err());
+        assert!(SlowConsumerPolicy::from_str("drop").is_err());
+
+        let config = run_config(&["--max-queued-objects", "100", "--slow-consumer-policy", "drop-newest"]);
+        assert_eq!(Some(100), config.queue.max_objects);
+        assert_eq!(SlowConsumerPolicy::DropNewest, config.queue.policy);
+    }
+
+    #[test]
+    fn should_parse_default_subscription() {
+        let rules = |rules: &[&str]| Some(BusinessSubscription::List(
+            rules.iter().map(|rule| BusinessSubscription::String(rule.to_string())).collect()));
+
+        assert_eq!(None, run_config(&[]).default_subscription);
+        assert_eq!(rules(&["@foo/*", "!#bar"]),
+                   run_config(&["--default-subscription", "@foo/*, !#bar"]).default_subscription);
+        assert_eq!(rules(&[]), run_config(&["--default-subscription", ""]).default_subscription);
+
+        let config = Config::from_json_str(r#"{"default-subscription": ["@foo/*"]}"#).unwrap();
+        assert_eq!(rules(&["@foo/*"]), config.default_subscription);
+        assert!(Config::from_json_str(r#"{"default-subscription": [1]}"#).is_err());
+        assert!(Config::from_json_str(r#"{"default-subscription": ["$count>many"]}"#).is_err());
+        assert!(parse(&["--default-subscription", "@foo/*, $>1"]).is_err());
+    }
+
+    #[test]
+    fn should_read_json_config() {
+        let config = Config::from_json_str(r#"{
+            "listen": ["127.0.0.1:7891", "unix:/tmp/rabboe.sock"],
+            "max-clients": 1000,
+            "read-buffer-size": "65536",
+            "max-buffered": null,
+            "keepalive-interval": "none"
+        }"#).unwrap();
+
+        assert_eq!(2, config.listen.len());
+        assert_eq!(Some(1000), config.max_clients);
+        assert_eq!(65536, config.read_buffer_size);
+        assert_eq!(None, config.limits.max_buffered);
+        assert_eq!(None, config.keepalive.interval);
+        assert_eq!(Some(60), run_config(&["--keepalive-interval", "60"]).keepalive.interval);
+
+        assert!(Config::from_json_str(r#"{"max-clients": 1.5}"#).is_err());
+        assert!(Config::from_json_str(r#"{"listen": [7890]}"#).is_err());
+        assert!(Config::from_json_str(r#"{"max-client": 10}"#).is_err());
+        assert!(Config::from_json_str("[]").is_err());
+    }
+}
@@ -0,0 +1,1536 @@
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::env;
+use std::fmt;
+use std::io::{Write, Error, ErrorKind};
+use std::io;
+use std::process;
+use std::rc::Rc;
+
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+use env_logger::LogBuilder;
+
+extern crate rustc_serialize;
+use rustc_serialize::json::{Json, ToJson};
+
+extern crate mio;
+use mio::*;
+use mio::util::Slab;
+
+extern crate time;
+use time::{Duration, Timespec};
+
+extern crate object_system;
+use object_system::{BusinessObject, BusinessObjectBuilder, ReadBusinessObjectError, SerializeBusinessObjectError};
+use object_system::io::*;
+use object_system::subscription;
+use object_system::subscription::{BusinessSubscription, BusinessSubscriptionError, RoutingKey, SubscriptionIndex};
+
+mod config;
+use config::{Command, Config, Keepalive, QueueLimits, SlowConsumerPolicy};
+
+mod net;
+use net::{ClientStream, Listener, PeerAddr};
+
+
+/// Parses the subscription in a `routing/subscribe` object. One without
+/// `subscriptions` gets `default`, if there is one.
+fn parse_subscription(obj: &BusinessObject, default: Option<&BusinessSubscription>)
+                      -> Result<BusinessSubscription, BusinessSubscriptionError> {
+    // trace!("Parsing subscription: {:?}", &obj.to_json());
+    match obj.event {
+        Some(ref event) => {
+            if event == "routing/subscribe" {
+                match obj.metadata.get("subscriptions") {
+                    Some(subscriptions) => {
+                        match subscription::parse_subscription(subscriptions) {
+                            Ok(subs) => Ok(subs),
+                            Err(e) => Err(e)
+                        }
+                    },
+                    None => match default {
+                        Some(default) => Ok(default.clone()),
+                        None => Err(BusinessSubscriptionError::NoSubscriptionMetadataKey)
+                    }
+                }
+            } else {
+                Err(BusinessSubscriptionError::UnknownSubscriptionEvent)
+            }
+        },
+        None => Err(BusinessSubscriptionError::SubscriptionNotEvent)
+    }
+}
+
+
+fn reply_builder(event: &str, request: &BusinessObject) -> BusinessObjectBuilder {
+    let builder = BusinessObject::builder().event(event);
+
+    match request.metadata.get("id").and_then(|id| id.as_string()) {
+        Some(id) => builder.metadata("in-reply-to", id),
+        None => builder
+    }
+}
+
+
+fn subscription_reply(subscriptions: &BusinessSubscription, request: &BusinessObject) -> BusinessObject {
+    reply_builder("routing/subscribe/reply", request)
+        .metadata("subscriptions", subscriptions)
+        .build()
+}
+
+
+fn ping_reply(request: &BusinessObject) -> BusinessObject {
+    reply_builder("pong", request).build()
+}
+
+
+/// A change to a client announced to the others.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Change {
+    Subscribed,
+    Resubscribed,
+    Disconnected
+}
+
+
+impl Change {
+    fn name(self) -> &'static str {
+        match self {
+            Change::Subscribed => "subscribed",
+            Change::Resubscribed => "resubscribed",
+            Change::Disconnected => "disconnected"
+        }
+    }
+}
+
+
+/// Describes `change` to `client` in a `routing/announcement`, for
+/// monitoring tools subscribed to `@routing/*`.
+fn announcement(client: &BusinessClient, change: Change) -> BusinessObject {
+    let builder = BusinessObject::builder()
+        .event("routing/announcement")
+        .metadata("change", change.name())
+        .metadata("client", &client.token.as_usize())
+        .metadata("peer", &client.peer_addr.to_string());
+
+    match client.subscription {
+        Some(ref subscription) => builder.metadata("subscriptions", subscription).build(),
+        None => builder.build()
+    }
+}
+
+
+/// Tells a client why it is being disconnected.
+fn disconnect_notice(reason: &str) -> BusinessObject {
+    BusinessObject::builder()
+        .event("routing/disconnect")
+        .metadata("reason", reason)
+        .build()
+}
+
+
+/// An object on its way out to clients. The frame is encoded once and shared
+/// by every client the object is routed to.
+#[derive(Debug)]
+struct OutgoingObject {
+    object: Rc<BusinessObject>,
+    frame: Frame<'static>
+}
+
+
+impl OutgoingObject {
+    fn new(object: Rc<BusinessObject>) -> Result<Rc<OutgoingObject>, SerializeBusinessObjectError> {
+        let frame = Frame::new(&object)?.into_shared();
+        Ok(Rc::new(OutgoingObject { object, frame }))
+    }
+}
+
+
+/// How often idle clients are checked on.
+const KEEPALIVE_TICK_MS: u64 = 1000;
+
+
+/// How many clients there is room for at first. The room is doubled
+/// whenever it runs out, up to `Config::max_clients`.
+const INITIAL_CLIENT_CAPACITY: usize = 128;
+
+
+struct Server {
+    config: Config,
+    listeners: Vec<Listener>,
+    clients: Slab<BusinessClient>,
+    client_capacity: usize,
+    /// The subscriptions of the subscribed clients, by token.
+    subscriptions: SubscriptionIndex<Token>,
+    /// Objects dropped for clients since disconnected.
+    dropped_for_departed: u64,
+}
+
+
+fn client_for_token(server: &mut Server, token: Token) -> &mut BusinessClient {
+    &mut server.clients[token]
+}
+
+
+impl Server {
+    fn new(config: Config, listeners: Vec<Listener>) -> Server {
+        // As per
+        // <https://github.com/hjr3/mob/blob/multi-echo-blog-post/src/main.rs>
+        // something else but actually our registered events come in with
+        // Token(0) by default. Listeners get the tokens from 1 on, clients
+        // the ones after them.
+        let first_client = Token(listeners.len() + 1);
+        let client_capacity = match config.max_clients {
+            Some(max_clients) => cmp::min(max_clients, INITIAL_CLIENT_CAPACITY),
+            None => INITIAL_CLIENT_CAPACITY
+        };
+
+        Server {
+            clients: Slab::new_starting_at(first_client, client_capacity),
+            client_capacity,
+            subscriptions: SubscriptionIndex::new(),
+            dropped_for_departed: 0,
+            config,
+            listeners
+        }
+    }
+
+    /// The index of the listener `token` belongs to, if it is a listener's.
+    fn listener_index(&self, token: Token) -> Option<usize> {
+        match token.as_usize() {
+            0 => None,
+            n if n <= self.listeners.len() => Some(n - 1),
+            _ => None
+        }
+    }
+
+    fn register(&mut self, event_loop: &mut EventLoop<Server>) -> io::Result<()> {
+        for (index, listener) in self.listeners.iter().enumerate() {
+            let token = Token(index + 1);
+            event_loop.register_opt(listener, token, EventSet::readable(),
+                                    PollOpt::edge() | PollOpt::oneshot()
+                                    ).map_err(|e| {
+                                        error!("Failed to register listener {:?}, {:?}", token, e);
+                                        e
+                                    })?;
+        }
+
+        Ok(())
+    }
+
+    fn reregister(&mut self, event_loop: &mut EventLoop<Server>, index: usize) {
+        let token = Token(index + 1);
+        event_loop.reregister(&self.listeners[index], token, EventSet::readable(),
+                              PollOpt::edge() | PollOpt::oneshot()
+                              ).unwrap_or_else(|e| {
+                                  error!("Failed to reregister listener {:?}, {:?}", token, e);
+                                  self.reset_connection(event_loop, token);
+                              })
+    }
+
+    fn new_client(&mut self, event_loop: &mut EventLoop<Server>, index: usize) {
+        // Log an error if there is no socket, but otherwise move on so we do not tear down the
+        // entire server.
+        let (sock, peer_addr) = match self.listeners[index].accept() {
+            Ok(Some((sock, peer_addr))) => {
+                info!("Accepted connection from {}", peer_addr);
+                (sock, peer_addr)
+            },
+            Ok(None) => {
+                error!("Failed to accept new socket");
+                self.reregister(event_loop, index);
+                return;
+            },
+            Err(e) => {
+                error!("Failed to accept new socket, {:?}", e);
+                self.reregister(event_loop, index);
+                return;
+            }
+        };
+
+        if !self.make_room_for_client() {
+            warn!("Turning away {}: already serving {} clients", peer_addr, self.clients.count());
+            reject_client(sock, "Too many clients");
+            self.reregister(event_loop, index);
+            return;
+        }
+
+        let config = &self.config;
+        match self.clients.insert_with(|token| {
+            trace!("Registering {:?} with event loop", token);
+            BusinessClient::new(sock, peer_addr, token, config)
+        }) {
+            Some(token) => {
+                match client_for_token(self, token).register(event_loop) {
+                    Ok(_) => {},
+                    Err(e) => {
+                        error!("Failed to register {:?} connection with event loop, {:?}", token, e);
+                        self.clients.remove(token);
+                    }
+                }
+            },
+            None => {
+                // Can't happen after making room, but if it does, `sock` has
+                // gone out of scope and been dropped.
+                error!("Failed to insert connection into slab");
+            }
+        };
+
+        // Re-register listener after received event
+        self.reregister(event_loop, index);
+    }
+
+    /// Makes sure there's room for one more client, growing the slab if the
+    /// configured maximum allows. Returns whether there's room.
+    fn make_room_for_client(&mut self) -> bool {
+        if self.clients.count() < self.client_capacity {
+            return true;
+        }
+
+        let grown = match self.config.max_clients {
+            Some(max_clients) => cmp::min(max_clients, self.client_capacity * 2),
+            None => self.client_capacity * 2
+        };
+        if grown == self.client_capacity {
+            return false;
+        }
+
+        debug!("Growing room for clients from {} to {}", self.client_capacity, grown);
+        self.clients.grow(grown - self.client_capacity);
+        self.client_capacity = grown;
+        true
+    }
+
+    fn readable(&mut self, event_loop: &mut EventLoop<Server>, token: Token) -> io::Result<()> {
+        trace!("Server conn readable, token: {:?}", token);
+        // The client may be gone already, disconnected by this same event.
+        if !self.clients.contains(token) {
+            return Ok(());
+        }
+        let objs_result = client_for_token(self, token).read_objects();
+
+        match objs_result {
+            Ok(frames) => {
+                for frame in frames.into_iter() {
+                    // Handling an object may disconnect its sender.
+                    if !self.clients.contains(token) {
+                        break;
+                    }
+
+                    match frame {
+                        Ok(obj) => {
+                            debug!("IN({}): {:?}", client_for_token(self, token).peer_addr, obj);
+                            self.handle_incoming_object(event_loop, token, Rc::new(obj));
+                        },
+                        Err(ref e) if e.is_limit_exceeded() => {
+                            warn!("Disconnecting {}: {}", client_for_token(self, token).peer_addr, e);
+                            return Err(Error::other(e.to_string()));
+                        },
+                        Err(e) => {
+                            warn!("Skipping frame from {}: {}", client_for_token(self, token).peer_addr, e);
+                        }
+                    }
+                }
+            },
+            Err(ref e) if e.is_limit_exceeded() => {
+                warn!("Disconnecting {}: {}", client_for_token(self, token).peer_addr, e);
+                return Err(Error::other(e.to_string()));
+            },
+            Err(e) => {
+                warn!("Couldn't read objects from {}: {}", client_for_token(self, token).peer_addr, e);
+            }
+        };
+
+
+        Ok(())
+    }
+
+    fn schedule_periodical(&mut self, event_loop: &mut EventLoop<Server>) {
+        if self.config.keepalive.interval.is_some() {
+            if let Err(e) = event_loop.timeout_ms((), KEEPALIVE_TICK_MS) {
+                error!("Failed to schedule keepalive, {:?}", e);
+            }
+        }
+    }
+
+    /// Pings idle clients and disconnects the ones that don't answer.
+    fn periodical(&mut self, event_loop: &mut EventLoop<Server>) {
+        let now = time::get_time();
+        let mut bad_tokens = Vec::new();
+
+        for client in self.clients.iter_mut() {
+            match client.keepalive(now, &self.config.keepalive) {
+                Ok(true) => {
+                    client.reregister(event_loop).unwrap_or_else(|_| bad_tokens.push(client.token));
+                },
+                Ok(false) => {},
+                Err(e) => {
+                    info!("Disconnecting {}: {}", client.peer_addr, e);
+                    bad_tokens.push(client.token);
+                }
+            }
+        }
+
+        for t in bad_tokens {
+            self.reset_connection(event_loop, t);
+        }
+    }
+
+    fn reset_connection(&mut self, event_loop: &mut EventLoop<Server>, token: Token) {
+        if self.listener_index(token).is_some() {
+            event_loop.shutdown();
+        } else {
+            trace!("Reset connection, token: {:?}", token);
+            if let Some(client) = self.clients.remove(token) {
+                self.subscriptions.remove(token);
+                if client.dropped > 0 {
+                    info!("Disconnected {}, having dropped {} objects for it", client.peer_addr, client.dropped);
+                }
+                self.dropped_for_departed += client.dropped;
+
+                if client.subscription.is_some() {
+                    let announcement = announcement(&client, Change::Disconnected);
+                    self.route(event_loop, Rc::new(announcement));
+                }
+            }
+        }
+    }
+
+    /// Describes the clients, their send queues and the objects dropped for
+    /// slow clients.
+    fn stats_reply(&self, request: &BusinessObject) -> BusinessObject {
+        let mut dropped = self.dropped_for_departed;
+        let mut clients = Vec::new();
+
+        for client in self.clients.iter() {
+            dropped += client.dropped;
+
+            let mut stats = BTreeMap::new();
+            stats.insert("client".to_string(), client.token.as_usize().to_json());
+            stats.insert("peer".to_string(), client.peer_addr.to_string().to_json());
+            stats.insert("queued-objects".to_string(), client.send_queue.len().to_json());
+            stats.insert("queued-bytes".to_string(), client.queued_bytes.to_json());
+            stats.insert("dropped".to_string(), client.dropped.to_json());
+            clients.push(Json::Object(stats));
+        }
+
+        reply_builder("routing/stats/reply", request)
+            .metadata("dropped", &dropped)
+            .metadata("clients", &clients)
+            .build()
+    }
+
+    /// Queues `object` for every subscribed client whose subscription it
+    /// matches.
+    fn route(&mut self, event_loop: &mut EventLoop<Server>, object: Rc<BusinessObject>) {
+        // A malformed object is dropped; it must not take the broker down.
+        let outgoing = match OutgoingObject::new(object.clone()) {
+            Ok(outgoing) => outgoing,
+            Err(e) => {
+                error!("Not routing object that can't be serialized: {}", e);
+                return;
+            }
+        };
+
+        let key = RoutingKey::from_object(&object);
+
+        let mut bad_tokens = Vec::new();
+
+        // Queue up a write for the clients subscribed to the object.
+        for token in self.subscriptions.lookup(&key) {
+            let client = match self.clients.get_mut(token) {
+                Some(client) => client,
+                None => continue
+            };
+
+            client.send_object(outgoing.clone())
+                .and_then(|_| client.reregister(event_loop))
+                .unwrap_or_else(|e| {
+                    error!("Failed to queue message for {:?}: {:?}", client.token, e);
+                    bad_tokens.push(client.token)
+                });
+        }
+
+        for t in bad_tokens {
+            self.reset_connection(event_loop, t);
+        }
+    }
+
+    /// Tells the clients watching `routing/*` about a change to the client of
+    /// `token`.
+    fn announce(&mut self, event_loop: &mut EventLoop<Server>, token: Token, change: Change) {
+        let announcement = announcement(&self.clients[token], change);
+        self.route(event_loop, Rc::new(announcement));
+    }
+
+    fn set_subscription(&mut self, token: Token, subscription: BusinessSubscription) {
+        self.subscriptions.insert(token, &subscription);
+        client_for_token(self, token).subscription = Some(subscription);
+    }
+
+    /// Queues `reply` for the client of `token`, disconnecting the client if
+    /// that fails. Returns whether the client is still connected.
+    fn send_reply(&mut self, event_loop: &mut EventLoop<Server>, token: Token, reply: BusinessObject) -> bool {
+        let queued = OutgoingObject::new(Rc::new(reply)).map_err(Error::other)
+            .and_then(|reply| client_for_token(self, token).send_object(reply))
+            .and_then(|_| client_for_token(self, token).reregister(event_loop));
+
+        match queued {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Failed to queue message for {:?}: {:?}", token, e);
+                self.reset_connection(event_loop, token);
+                false
+            }
+        }
+    }
+
+    /// Handles a `routing/subscribe` from the client of `token`. A client
+    /// that already has a subscription gets it replaced, or kept as it was
+    /// if the new one is no good. A client whose first subscription is no
+    /// good gets the default subscription, if there is one, and is
+    /// disconnected otherwise.
+    fn subscribe(&mut self, event_loop: &mut EventLoop<Server>, token: Token, object: &BusinessObject) {
+        let resubscribing = client_for_token(self, token).subscription.is_some();
+
+        match parse_subscription(object, self.config.default_subscription.as_ref()) {
+            Ok(subscription) => {
+                let reply = subscription_reply(&subscription, object);
+                if !self.send_reply(event_loop, token, reply) {
+                    return;
+                }
+                self.set_subscription(token, subscription);
+
+                let change = if resubscribing { Change::Resubscribed } else { Change::Subscribed };
+                self.announce(event_loop, token, change);
+            },
+            Err(e) => match (resubscribing, self.config.default_subscription.clone()) {
+                (true, _) => {
+                    warn!("Couldn't parse new subscription from {}, keeping the old one: {:?}",
+                          client_for_token(self, token).peer_addr, e);
+                    let reply = reply_builder("routing/subscribe/reply", object)
+                        .metadata("error", &format!("{:?}", e))
+                        .build();
+                    self.send_reply(event_loop, token, reply);
+                },
+                (false, Some(subscription)) => {
+                    warn!("Couldn't parse subscription from {}, giving it the default one: {:?}",
+                          client_for_token(self, token).peer_addr, e);
+                    let reply = reply_builder("routing/subscribe/reply", object)
+                        .metadata("subscriptions", &subscription)
+                        .metadata("error", &format!("{:?}", e))
+                        .build();
+                    if !self.send_reply(event_loop, token, reply) {
+                        return;
+                    }
+                    self.set_subscription(token, subscription);
+                    self.announce(event_loop, token, Change::Subscribed);
+                },
+                (false, None) => {
+                    warn!("Couldn't parse subscription from client: {:?}", e);
+                    self.reset_connection(event_loop, token);
+                }
+            }
+        }
+    }
+
+    fn handle_incoming_object(&mut self, event_loop: &mut EventLoop<Server>,
+                               token: Token, object: Rc<BusinessObject>) {
+        client_for_token(self, token).last_activity = time::get_time();
+
+        // Answers to keepalive pings are for us alone, subscribed or not.
+        if client_for_token(self, token).take_pong(&object) {
+            trace!("Keepalive answered by {:?}", token);
+            return;
+        }
+
+        match client_for_token(self, token).subscription {
+            Some(_) => {
+                trace!("Would handle {:?}", &object);
+
+                let is_ping = match object.event { Some(ref event) => event == "ping",
+                                                   None => false };
+                let is_stats = match object.event { Some(ref event) => event == "routing/stats",
+                                                    None => false };
+                let is_subscribe = match object.event { Some(ref event) => event == "routing/subscribe",
+                                                        None => false };
+
+                let mut bad_tokens = Vec::new();
+                if is_subscribe {
+                    self.subscribe(event_loop, token, &object);
+                } else if is_stats {
+                    let reply = self.stats_reply(&object);
+                    OutgoingObject::new(Rc::new(reply)).map_err(Error::other)
+                        .and_then(|reply| client_for_token(self, token).send_object(reply))
+                        .and_then(|_| client_for_token(self, token).reregister(event_loop))
+                        .unwrap_or_else(|e| {
+                            error!("Failed to queue message for {:?}: {:?}", token, e);
+                            bad_tokens.push(token)
+                        });
+                } else if is_ping {
+                    let key = RoutingKey::new(None, Some("pong"), None);
+                    let decision = self.subscriptions.matches(token, &key);
+
+                    let pong = ping_reply(&object);
+                    if decision {
+                        OutgoingObject::new(Rc::new(pong)).map_err(Error::other)
+                            .and_then(|pong| client_for_token(self, token).send_object(pong))
+                            .and_then(|_| client_for_token(self, token).reregister(event_loop))
+                            .unwrap_or_else(|e| {
+                                error!("Failed to queue message for {:?}: {:?}", token, e);
+                                bad_tokens.push(token)
+                            });
+                    }
+                } else {
+                    self.route(event_loop, object);
+                }
+
+                for t in bad_tokens {
+                    self.reset_connection(event_loop, t);
+                }
+            },
+            None => {
+                let is_subscribe = match object.event { Some(ref event) => event == "routing/subscribe",
+                                                        None => false };
+                if let (false, Some(subscription)) = (is_subscribe, self.config.default_subscription.clone()) {
+                    debug!("Giving {:?} the default subscription", token);
+                    self.set_subscription(token, subscription);
+                    self.announce(event_loop, token, Change::Subscribed);
+
+                    // Now that the client is subscribed, its object is routed like any other.
+                    if self.clients.contains(token) {
+                        self.handle_incoming_object(event_loop, token, object);
+                    }
+                    return;
+                }
+
+                trace!("Would subscribe {:?}", &object);
+                self.subscribe(event_loop, token, &object);
+            }
+        }
+    }
+}
+
+
+impl Handler for Server {
+    type Timeout = ();
+    type Message = ();
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<Server>, _: ()) {
+        self.periodical(event_loop);
+        self.schedule_periodical(event_loop);
+    }
+
+    fn ready(&mut self, event_loop: &mut EventLoop<Server>, token: Token, events: EventSet) {
+        trace!("Events = {:?}", events);
+        assert!(token != Token(0), "[BUG]: Received event for Token(0)");
+
+        if events.is_error() {
+            warn!("Error event for {:?}", token);
+            self.reset_connection(event_loop, token);
+            return;
+        }
+
+        if events.is_hup() {
+            trace!("Hup event for {:?}", token);
+            self.reset_connection(event_loop, token);
+            return;
+        }
+
+        // We never expect a write event for a listener token. A write event for any other token
+        // should be handed off to that connection.
+        if events.is_writable() {
+            trace!("Write event for {:?}", token);
+            assert!(self.listener_index(token).is_none(), "Received writable event for a listener");
+
+            // The client may be gone already, disconnected while handling an
+            // earlier event of the same batch.
+            if self.clients.contains(token) {
+                client_for_token(self, token).writable()
+                    .and_then(|_| client_for_token(self, token).reregister(event_loop))
+                    .unwrap_or_else(|e| {
+                        warn!("Write event failed for {:?}, {:?}", token, e);
+                        self.reset_connection(event_loop, token);
+                    });
+            }
+        }
+
+        if events.is_readable() {
+            trace!("Read event for {:?}", token);
+            if let Some(index) = self.listener_index(token) {
+                self.new_client(event_loop, index);
+            } else {
+                self.readable(event_loop, token)
+                    .and_then(|_| match self.clients.get_mut(token) {
+                        Some(client) => client.reregister(event_loop),
+                        None => Ok(())
+                    })
+                    .unwrap_or_else(|e| {
+                        warn!("Read event failed for {:?}: {:?}", token, e);
+                        self.reset_connection(event_loop, token);
+                    });
+            }
+        }
+    }
+}
+
+
+/// Sends `sock` a disconnect notice giving `reason` and closes it. The notice
+/// is small enough to fit in a fresh socket's send buffer; if it doesn't, the
+/// client is closed without it.
+fn reject_client(mut sock: ClientStream, reason: &str) {
+    let sent = Frame::new(&disconnect_notice(reason))
+        .map_err(Error::other)
+        .and_then(|frame| frame.write_all(&mut sock));
+    if let Err(e) = sent {
+        debug!("Couldn't send disconnect notice: {}", e);
+    }
+}
+
+
+struct BusinessClient {
+    stream: BusinessObjectStream<ClientStream>,
+    token: Token,
+    interest: EventSet,
+    /// Objects waiting to be sent, oldest first.
+    send_queue: VecDeque<Rc<OutgoingObject>>,
+    /// How much of the frame at the front of `send_queue` has been written.
+    write_offset: usize,
+    /// Total length of the frames in `send_queue`.
+    queued_bytes: usize,
+    queue_limits: QueueLimits,
+    /// Objects dropped because the client didn't keep up.
+    dropped: u64,
+
+    subscription: Option<BusinessSubscription>,
+    last_activity: Timespec,
+    /// The id of the keepalive ping awaiting an answer, and when it was sent.
+    pending_ping: Option<(String, Timespec)>,
+    pings_sent: u64,
+
+    peer_addr: PeerAddr
+}
+
+
+impl fmt::Debug for BusinessClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let timestamp = match time::strftime("%Y-%m-%dT%H:%M:%S",
+                                             &time::at_utc(self.last_activity)) {
+            Ok(ts) => ts,
+            Err(_) => "Couldn't format".to_string()
+        };
+
+        write!(f, "BusinessClient(token: {}, last_activity: {}, peer: {}, subscription: {:?}, \
+                   queued: {} objects/{} bytes, dropped: {})",
+               self.token.as_usize(),
+               timestamp,
+               self.peer_addr,
+               self.subscription,
+               self.send_queue.len(),
+               self.queued_bytes,
+               self.dropped)
+    }
+}
+
+
+impl BusinessClient {
+    fn new(socket: ClientStream, peer_addr: PeerAddr, token: Token, config: &Config) -> BusinessClient {
+        let mut stream = BusinessObjectStream::with_limits(socket, config.limits);
+        stream.set_read_buffer_size(config.read_buffer_size);
+
+        BusinessClient {
+            peer_addr,
+
+            stream,
+            token,
+
+            interest: EventSet::hup(),
+
+            send_queue: VecDeque::new(),
+            write_offset: 0,
+            queued_bytes: 0,
+            queue_limits: config.queue.clone(),
+            dropped: 0,
+
+            subscription: Option::None,
+            last_activity: time::get_time(),
+            pending_ping: None,
+            pings_sent: 0,
+
+        }
+    }
+
+    /// Pings the client once it has been quiet for the keepalive interval.
+    /// Fails if a ping has gone unanswered for longer than the timeout.
+    /// Returns whether a ping was queued.
+    fn keepalive(&mut self, now: Timespec, keepalive: &Keepalive) -> io::Result<bool> {
+        let interval = match keepalive.interval {
+            Some(interval) => Duration::seconds(interval as i64),
+            None => return Ok(false)
+        };
+
+        if let Some((_, sent)) = self.pending_ping {
+            if now - sent >= Duration::seconds(keepalive.timeout as i64) {
+                return Err(Error::new(ErrorKind::TimedOut,
+                                      format!("No pong in {} seconds", keepalive.timeout)));
+            }
+            return Ok(false);
+        }
+
+        if now - self.last_activity < interval {
+            return Ok(false);
+        }
+
+        self.pings_sent += 1;
+        let id = format!("keepalive-{}", self.pings_sent);
+        let ping = BusinessObject::builder().event("ping").metadata("id", &id).build();
+        // A ping dropped for a full queue isn't waited on; the next tick tries again.
+        if !self.send_object(OutgoingObject::new(Rc::new(ping)).map_err(Error::other)?)? {
+            return Ok(false);
+        }
+        self.pending_ping = Some((id, now));
+        Ok(true)
+    }
+
+    /// Whether `object` is the pong to the pending keepalive ping, which it
+    /// then clears. A pong without `in-reply-to` is taken as an answer too.
+    fn take_pong(&mut self, object: &BusinessObject) -> bool {
+        let is_pong = match object.event { Some(ref event) => event == "pong", None => false };
+        let answers = match (&self.pending_ping, object.metadata.get("in-reply-to")) {
+            (&Some((ref id, _)), Some(reply_to)) => reply_to.as_string() == Some(id),
+            (&Some(_), None) => true,
+            (&None, _) => false
+        };
+
+        if is_pong && answers {
+            self.pending_ping = None;
+        }
+        is_pong && answers
+    }
+
+    fn read_objects(&mut self) -> Result<Vec<FrameResult>, ReadBusinessObjectError> {
+        self.stream.read_frames()
+    }
+
+    /// Writes queued frames until the queue is empty or the socket is full.
+    /// A frame the socket only takes part of stays at the front of the queue
+    /// and is resumed from `write_offset` on the next writable event.
+    fn writable(&mut self) -> io::Result<()> {
+        while let Some(object) = self.send_queue.front().cloned() {
+            let frame_len = object.frame.len();
+            match object.frame.write_from(&mut self.stream, self.write_offset) {
+                Ok(n) => {
+                    trace!("CONN : we wrote {} bytes", n);
+                    self.write_offset += n;
+                    if self.write_offset < frame_len {
+                        debug!("Wrote {} of {} bytes to {:?}, resuming later",
+                               self.write_offset, frame_len, self.token);
+                        break;
+                    }
+
+                    self.send_queue.pop_front();
+                    self.write_offset = 0;
+                    self.queued_bytes -= frame_len;
+                    debug!("Sent object to {:?}", self);
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    trace!("Socket of {:?} is full, {} objects queued", self.token, self.send_queue.len());
+                    break;
+                },
+                Err(e) => {
+                    error!("Failed to send buffer for {:?}, error: {}", self.token, e);
+                    return Err(e);
+                }
+            }
+        }
+
+        let _ = self.stream.flush();
+
+        if self.send_queue.is_empty() {
+            self.interest.remove(EventSet::writable());
+        }
+
+        Ok(())
+    }
+
+    /// Queues `object` for sending. When the queue is full, the slow consumer
+    /// policy decides what gets dropped; this fails when it says to
+    /// disconnect the client instead. Returns whether `object` was queued
+    /// rather than dropped.
+    fn send_object(&mut self, object: Rc<OutgoingObject>) -> io::Result<bool> {
+        debug!("OUT({}): {:?}", self.peer_addr, object.object);
+        let frame_len = object.frame.len();
+
+        if !self.queue_fits(frame_len) {
+            let policy = self.queue_limits.policy.clone();
+            let fits = match policy {
+                SlowConsumerPolicy::Disconnect => false,
+                SlowConsumerPolicy::DropNewest => {
+                    self.count_drop(&object);
+                    return Ok(false);
+                },
+                SlowConsumerPolicy::DropOldest => {
+                    if !self.drop_queued_until_fits(frame_len, |_| true) {
+                        // Larger than the whole queue may be.
+                        self.count_drop(&object);
+                        return Ok(false);
+                    }
+                    true
+                },
+                SlowConsumerPolicy::DropNatures(ref natures) => {
+                    let droppable = |object: &OutgoingObject| {
+                        object.object.natures().iter().any(|nature| natures.iter().any(|n| n == nature))
+                    };
+                    if self.drop_queued_until_fits(frame_len, droppable) {
+                        true
+                    } else if droppable(&object) {
+                        self.count_drop(&object);
+                        return Ok(false);
+                    } else {
+                        false
+                    }
+                }
+            };
+
+            if !fits {
+                return Err(Error::other(format!("Send queue full with {} objects, {} bytes",
+                                                self.send_queue.len(), self.queued_bytes)));
+            }
+        }
+
+        self.queued_bytes += frame_len;
+        self.send_queue.push_back(object);
+        self.interest.insert(EventSet::writable());
+        Ok(true)
+    }
+
+    fn queue_fits(&self, frame_len: usize) -> bool {
+        let fits_objects = match self.queue_limits.max_objects {
+            Some(max_objects) => self.send_queue.len() < max_objects,
+            None => true
+        };
+        let fits_bytes = match self.queue_limits.max_bytes {
+            Some(max_bytes) => self.queued_bytes + frame_len <= max_bytes,
+            None => true
+        };
+
+        fits_objects && fits_bytes
+    }
+
+    /// Drops queued objects for which `droppable` holds, oldest first, until
+    /// a frame of `frame_len` fits. A frame already partly written is never
+    /// dropped. Returns whether the frame fits.
+    fn drop_queued_until_fits<F>(&mut self, frame_len: usize, droppable: F) -> bool
+        where F: Fn(&OutgoingObject) -> bool
+    {
+        let mut index = if self.write_offset > 0 { 1 } else { 0 };
+
+        while !self.queue_fits(frame_len) && index < self.send_queue.len() {
+            if droppable(&self.send_queue[index]) {
+                let dropped = self.send_queue.remove(index).unwrap();
+                self.queued_bytes -= dropped.frame.len();
+                self.count_drop(&dropped);
+            } else {
+                index += 1;
+            }
+        }
+
+        self.queue_fits(frame_len)
+    }
+
+    fn count_drop(&mut self, object: &OutgoingObject) {
+        if self.dropped == 0 {
+            warn!("{} isn't keeping up, dropping objects ({:?})", self.peer_addr, self.queue_limits.policy);
+        }
+        debug!("Dropped {:?} for {}", object.object, self.peer_addr);
+        self.dropped += 1;
+    }
+
+    fn register(&mut self, event_loop: &mut EventLoop<Server>) -> io::Result<()> {
+        self.interest.insert(EventSet::readable());
+
+        event_loop.register_opt(&self.stream.socket, self.token, self.interest, 
+                                PollOpt::edge() | PollOpt::oneshot()
+                                ).map_err(|e| {
+                                    error!("Failed to register {:?}, {:?}", self.token, e);
+                                    e
+                                })
+    }
+
+    fn reregister(&mut self, event_loop: &mut EventLoop<Server>) -> io::Result<()> {
+        event_loop.reregister(&self.stream.socket, self.token, self.interest,
+                              PollOpt::edge() | PollOpt::oneshot()
+                              ).map_err(|e| {
+                                  error!("Failed to reregister {:?}, {:?}", self.token, e);
+                                  e
+                              })
+    }
+}
+
+
+fn main() {
+    let config = match config::parse_args(env::args().skip(1)) {
+        Ok(Command::Run(config)) => *config,
+        Ok(Command::Help) => {
+            println!("{}", config::USAGE);
+            return;
+        },
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, config::USAGE);
+            process::exit(2);
+        }
+    };
+
+    let mut logger = LogBuilder::new();
+    match env::var("RUST_LOG") {
+        Ok(filters) => { logger.parse(&filters); },
+        Err(_) => if let Some(ref filters) = config.log_level { logger.parse(filters); }
+    }
+    logger.init().expect("Failed to init logger");
+
+    let listeners: Vec<Listener> = config.listen.iter()
+        .map(|addr| {
+            let listener = Listener::bind(addr, config.unix_socket_mode).expect("Failed to bind address");
+            info!("Listening on {}", listener.addr().as_ref().unwrap_or(addr));
+            listener
+        })
+        .collect();
+
+    let mut event_loop = EventLoop::new().expect("Failed to create event loop");
+
+    let mut server = Server::new(config, listeners);
+    server.register(&mut event_loop).expect("Failed to register server with event loop");
+    server.schedule_periodical(&mut event_loop);
+
+    info!("Server starting...");
+    event_loop.run(&mut server).expect("Failed to start event loop");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpStream as StdTcpStream};
+    use std::rc::Rc;
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::Duration as StdDuration;
+
+    use mio::{EventLoop, EventSet, Handler, Token};
+    use mio::tcp::TcpListener;
+
+    use time::Duration;
+
+    use object_system::BusinessObject;
+    use object_system::io::{BusinessObjectStream, ReadBusinessObject};
+
+    use config::{Config, Keepalive, SlowConsumerPolicy};
+    use net::{ClientStream, ListenAddr, Listener, PeerAddr};
+
+    use rustc_serialize::json::Json;
+
+    use object_system::subscription::BusinessSubscription;
+
+    use super::{announcement, BusinessClient, Change, OutgoingObject, Server, INITIAL_CLIENT_CAPACITY};
+
+    /// A client connected over loopback TCP, and the other end of its socket.
+    fn connected_client() -> (BusinessClient, StdTcpStream) {
+        connected_client_with(&Config::default())
+    }
+
+    fn connected_client_with(config: &Config) -> (BusinessClient, StdTcpStream) {
+        let (sock, peer_addr, peer) = connected_socket();
+        (BusinessClient::new(sock, peer_addr, Token(2), config), peer)
+    }
+
+    /// The server end of a loopback TCP connection, and the other end.
+    fn connected_socket() -> (ClientStream, PeerAddr, StdTcpStream) {
+        let addr: SocketAddr = FromStr::from_str("127.0.0.1:0").unwrap();
+        let listener = TcpListener::bind(&addr).unwrap();
+        let peer = StdTcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        loop {
+            if let Some(sock) = listener.accept().unwrap() {
+                let peer_addr = PeerAddr::Tcp(peer.local_addr().unwrap());
+                return (ClientStream::Tcp(sock), peer_addr, peer);
+            }
+            thread::sleep(StdDuration::from_millis(1));
+        }
+    }
+
+    /// A server listening on a loopback TCP port, registered with `event_loop`.
+    fn listening_server(config: Config, event_loop: &mut EventLoop<Server>) -> Server {
+        let listener = Listener::bind(&ListenAddr::from_str("127.0.0.1:0").unwrap(), None).unwrap();
+        let mut server = Server::new(config, vec![listener]);
+        server.register(event_loop).unwrap();
+        server
+    }
+
+    /// Connects to the server's listener and has the server accept the
+    /// connection, returning the other end of the socket.
+    fn accept_client(server: &mut Server, event_loop: &mut EventLoop<Server>) -> StdTcpStream {
+        let addr = match server.listeners[0].addr().unwrap() {
+            ListenAddr::Tcp(addr) => addr,
+            ListenAddr::Unix(_) => unreachable!()
+        };
+        let peer = StdTcpStream::connect(addr).unwrap();
+        server.new_client(event_loop, 0);
+        peer
+    }
+
+    /// Connects a client to `server`, returning its token and the other end
+    /// of its socket.
+    fn add_client(server: &mut Server) -> (Token, StdTcpStream) {
+        let (sock, peer_addr, peer) = connected_socket();
+        let config = server.config.clone();
+        let token = server.clients.insert_with(|token| BusinessClient::new(sock, peer_addr, token, &config)).unwrap();
+        (token, peer)
+    }
+
+    fn subscribe_object(rules: &[&str]) -> Rc<BusinessObject> {
+        let rules: Vec<String> = rules.iter().map(|rule| rule.to_string()).collect();
+        Rc::new(BusinessObject::builder().event("routing/subscribe").metadata("subscriptions", &rules).build())
+    }
+
+    fn config_subscription(rules: &[&str]) -> Option<BusinessSubscription> {
+        Some(BusinessSubscription::List(rules.iter().map(|rule| BusinessSubscription::String(rule.to_string())).collect()))
+    }
+
+    fn event(event: &str) -> Rc<BusinessObject> {
+        Rc::new(BusinessObject::builder().event(event).build())
+    }
+
+    fn outgoing(event: &str) -> Rc<OutgoingObject> {
+        OutgoingObject::new(Rc::new(BusinessObject::builder().event(event).build())).unwrap()
+    }
+
+    fn outgoing_with_natures(event: &str, natures: &[&str]) -> Rc<OutgoingObject> {
+        let object = BusinessObject::builder().event(event).natures(natures.iter().cloned()).build();
+        OutgoingObject::new(Rc::new(object)).unwrap()
+    }
+
+    fn client_with_queue(max_objects: usize, policy: SlowConsumerPolicy) -> (BusinessClient, StdTcpStream) {
+        let mut config = Config::default();
+        config.queue.max_objects = Some(max_objects);
+        config.queue.policy = policy;
+        connected_client_with(&config)
+    }
+
+    fn queued_events(client: &BusinessClient) -> Vec<String> {
+        client.send_queue.iter().map(|object| object.object.event.clone().unwrap()).collect()
+    }
+
+    #[test]
+    fn should_disconnect_slow_consumers() {
+        let (mut client, _peer) = client_with_queue(2, SlowConsumerPolicy::Disconnect);
+
+        client.send_object(outgoing("test/0")).unwrap();
+        client.send_object(outgoing("test/1")).unwrap();
+        assert!(client.send_object(outgoing("test/2")).is_err());
+    }
+
+    #[test]
+    fn should_drop_oldest_for_slow_consumers() {
+        let (mut client, _peer) = client_with_queue(3, SlowConsumerPolicy::DropOldest);
+
+        for i in 0 .. 5 {
+            client.send_object(outgoing(&format!("test/{}", i))).unwrap();
+        }
+        assert_eq!(vec!["test/2", "test/3", "test/4"], queued_events(&client));
+        assert_eq!(2, client.dropped);
+    }
+
+    #[test]
+    fn should_not_drop_partially_written_object() {
+        let (mut client, _peer) = client_with_queue(2, SlowConsumerPolicy::DropOldest);
+
+        client.send_object(outgoing("test/0")).unwrap();
+        client.send_object(outgoing("test/1")).unwrap();
+        client.write_offset = 1;
+        client.send_object(outgoing("test/2")).unwrap();
+        assert_eq!(vec!["test/0", "test/2"], queued_events(&client));
+    }
+
+    #[test]
+    fn should_drop_newest_for_slow_consumers() {
+        let (mut client, _peer) = client_with_queue(3, SlowConsumerPolicy::DropNewest);
+
+        for i in 0 .. 5 {
+            client.send_object(outgoing(&format!("test/{}", i))).unwrap();
+        }
+        assert_eq!(vec!["test/0", "test/1", "test/2"], queued_events(&client));
+        assert_eq!(2, client.dropped);
+    }
+
+    #[test]
+    fn should_drop_only_given_natures_for_slow_consumers() {
+        let policy = SlowConsumerPolicy::DropNatures(vec!["volatile".to_string()]);
+        let (mut client, _peer) = client_with_queue(3, policy);
+
+        client.send_object(outgoing_with_natures("test/0", &["volatile"])).unwrap();
+        client.send_object(outgoing("test/1")).unwrap();
+        client.send_object(outgoing_with_natures("test/2", &["hearing", "volatile"])).unwrap();
+        client.send_object(outgoing("test/3")).unwrap();
+        assert_eq!(vec!["test/1", "test/2", "test/3"], queued_events(&client));
+
+        client.send_object(outgoing("test/4")).unwrap();
+        assert_eq!(vec!["test/1", "test/3", "test/4"], queued_events(&client));
+
+        client.send_object(outgoing_with_natures("test/5", &["volatile"])).unwrap();
+        assert_eq!(vec!["test/1", "test/3", "test/4"], queued_events(&client));
+        assert_eq!(3, client.dropped);
+
+        assert!(client.send_object(outgoing("test/6")).is_err());
+    }
+
+    #[test]
+    fn should_bound_queued_bytes() {
+        let mut config = Config::default();
+        let frame_len = outgoing("test/0").frame.len();
+        config.queue.max_bytes = Some(2 * frame_len);
+        config.queue.policy = SlowConsumerPolicy::DropOldest;
+        let (mut client, _peer) = connected_client_with(&config);
+
+        for i in 0 .. 3 {
+            client.send_object(outgoing(&format!("test/{}", i))).unwrap();
+        }
+        assert_eq!(vec!["test/1", "test/2"], queued_events(&client));
+        assert_eq!(2 * frame_len, client.queued_bytes);
+    }
+
+    #[test]
+    fn should_grow_room_for_clients_past_initial_capacity() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut server = listening_server(Config::default(), &mut event_loop);
+
+        let _peers: Vec<StdTcpStream> = (0 .. INITIAL_CLIENT_CAPACITY + 1)
+            .map(|_| accept_client(&mut server, &mut event_loop))
+            .collect();
+
+        assert_eq!(INITIAL_CLIENT_CAPACITY + 1, server.clients.count());
+        assert_eq!(2 * INITIAL_CLIENT_CAPACITY, server.client_capacity);
+    }
+
+    #[test]
+    fn should_turn_away_clients_over_max_clients() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let config = Config { max_clients: Some(2), ..Config::default() };
+        let mut server = listening_server(config, &mut event_loop);
+
+        let _first = accept_client(&mut server, &mut event_loop);
+        let _second = accept_client(&mut server, &mut event_loop);
+        let turned_away = accept_client(&mut server, &mut event_loop);
+        assert_eq!(2, server.clients.count());
+        assert_eq!(2, server.client_capacity);
+
+        let mut stream = BusinessObjectStream::new(turned_away);
+        let mut received = Vec::new();
+        while received.is_empty() {
+            received.extend(stream.read_business_objects().unwrap());
+        }
+        assert_eq!(1, received.len());
+        assert_eq!(Some("routing/disconnect".to_string()), received[0].event);
+        assert_eq!(Some("Too many clients"), received[0].metadata["reason"].as_string());
+    }
+
+    #[test]
+    fn should_ignore_writable_event_for_disconnected_slow_consumer() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut config = Config::default();
+        config.queue.max_objects = Some(1);
+        let mut server = listening_server(config, &mut event_loop);
+        let _peer = accept_client(&mut server, &mut event_loop);
+        let client = server.clients.iter().next().unwrap().token;
+
+        let rules = vec!["@foo/*".to_string()];
+        let subscribe = BusinessObject::builder().event("routing/subscribe").metadata("subscriptions", &rules).build();
+        server.handle_incoming_object(&mut event_loop, client, Rc::new(subscribe));
+        server.handle_incoming_object(&mut event_loop, client, Rc::new(BusinessObject::builder().event("foo/bar").build()));
+        assert!(!server.clients.contains(client));
+
+        server.ready(&mut event_loop, client, EventSet::writable());
+    }
+
+    #[test]
+    fn should_resume_partially_written_objects() {
+        let (mut client, peer) = connected_client();
+        let large = BusinessObject::builder().event("test/large").payload(vec![7; 8 * 1024 * 1024]).build();
+        let small = BusinessObject::builder().event("test/small").build();
+
+        for object in &[&small, &large, &small] {
+            client.send_object(OutgoingObject::new(Rc::new((*object).clone())).unwrap()).unwrap();
+        }
+
+        // Nobody reads yet, so the socket fills up part way into `large`.
+        client.writable().unwrap();
+        assert_eq!(2, client.send_queue.len());
+        assert!(client.write_offset > 0);
+
+        let reader = thread::spawn(move || {
+            let mut stream = BusinessObjectStream::new(peer);
+            let mut received = Vec::new();
+            while received.len() < 3 {
+                received.extend(stream.read_business_objects().unwrap());
+            }
+            received
+        });
+
+        while !client.send_queue.is_empty() {
+            client.writable().unwrap();
+            thread::sleep(StdDuration::from_millis(1));
+        }
+
+        let received = reader.join().unwrap();
+        assert!(small.equals_strict(&received[0]));
+        assert!(large.equals_strict(&received[1]));
+        assert!(small.equals_strict(&received[2]));
+    }
+
+    #[test]
+    fn should_ping_idle_clients_and_time_out_silent_ones() {
+        let keepalive = Keepalive { interval: Some(60), timeout: 30 };
+        let (mut client, _peer) = connected_client();
+        let start = client.last_activity;
+
+        assert!(!client.keepalive(start + Duration::seconds(59), &keepalive).unwrap());
+        assert!(client.keepalive(start + Duration::seconds(60), &keepalive).unwrap());
+        assert_eq!(vec!["ping"], queued_events(&client));
+
+        assert!(!client.keepalive(start + Duration::seconds(89), &keepalive).unwrap());
+        assert!(client.keepalive(start + Duration::seconds(90), &keepalive).is_err());
+    }
+
+    #[test]
+    fn should_take_pong_to_keepalive_ping() {
+        let keepalive = Keepalive { interval: Some(60), timeout: 30 };
+        let (mut client, _peer) = connected_client();
+        let start = client.last_activity;
+        let pong = |reply_to: &str| BusinessObject::builder().event("pong").metadata("in-reply-to", reply_to).build();
+
+        assert!(!client.take_pong(&pong("keepalive-1")));
+
+        client.keepalive(start + Duration::seconds(60), &keepalive).unwrap();
+        assert!(!client.take_pong(&pong("someone-elses")));
+        assert!(!client.take_pong(&BusinessObject::builder().event("foo/bar").build()));
+        assert!(client.take_pong(&pong("keepalive-1")));
+        assert!(client.pending_ping.is_none());
+
+        client.last_activity = start + Duration::seconds(60);
+        assert!(!client.keepalive(start + Duration::seconds(100), &keepalive).unwrap());
+    }
+
+    #[test]
+    fn should_not_wait_for_ping_dropped_from_full_queue() {
+        let keepalive = Keepalive { interval: Some(60), timeout: 30 };
+        let (mut client, _peer) = client_with_queue(1, SlowConsumerPolicy::DropNewest);
+        let start = client.last_activity;
+        client.send_object(outgoing("foo/bar")).unwrap();
+
+        assert!(!client.keepalive(start + Duration::seconds(60), &keepalive).unwrap());
+        assert!(client.pending_ping.is_none());
+        assert!(client.keepalive(start + Duration::seconds(120), &keepalive).is_ok());
+
+        client.send_queue.clear();
+        client.queued_bytes = 0;
+        assert!(client.keepalive(start + Duration::seconds(121), &keepalive).unwrap());
+        assert!(client.pending_ping.is_some());
+    }
+
+    #[test]
+    fn should_not_ping_when_keepalive_is_off() {
+        let keepalive = Keepalive { interval: None, timeout: 30 };
+        let (mut client, _peer) = connected_client();
+        let start = client.last_activity;
+
+        assert!(!client.keepalive(start + Duration::seconds(3600), &keepalive).unwrap());
+        assert!(client.send_queue.is_empty());
+    }
+
+    #[test]
+    fn should_describe_client_in_announcement() {
+        let (mut client, peer) = connected_client();
+        client.subscription = Some(BusinessSubscription::List(vec![BusinessSubscription::String("@routing/*".to_string())]));
+
+        let announced = announcement(&client, Change::Subscribed);
+        assert_eq!(Some("routing/announcement".to_string()), announced.event);
+        assert_eq!(Some("subscribed"), announced.metadata["change"].as_string());
+        assert_eq!(Some(2), announced.metadata["client"].as_u64());
+        assert_eq!(Some(peer.local_addr().unwrap().to_string().as_ref()), announced.metadata["peer"].as_string());
+        assert_eq!(Some(&vec![Json::String("@routing/*".to_string())]),
+                   announced.metadata["subscriptions"].as_array());
+    }
+
+    #[test]
+    fn should_give_default_subscription_to_client_sending_first() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let config = Config { default_subscription: config_subscription(&["@bar/*"]), ..Config::default() };
+        let mut server = listening_server(config, &mut event_loop);
+        let _watcher_peer = accept_client(&mut server, &mut event_loop);
+        let _client_peer = accept_client(&mut server, &mut event_loop);
+        let tokens: Vec<Token> = server.clients.iter().map(|client| client.token).collect();
+        let (watcher, client) = (tokens[0], tokens[1]);
+
+        server.handle_incoming_object(&mut event_loop, watcher, subscribe_object(&["@routing/*", "@foo/*"]));
+        server.handle_incoming_object(&mut event_loop, client, event("foo/bar"));
+
+        assert_eq!(config_subscription(&["@bar/*"]), server.clients[client].subscription);
+        assert_eq!(vec!["routing/subscribe/reply", "routing/announcement", "routing/announcement", "foo/bar"],
+                   queued_events(&server.clients[watcher]));
+        let announced = &server.clients[watcher].send_queue[2].object;
+        assert_eq!(Some("subscribed"), announced.metadata["change"].as_string());
+        assert_eq!(Some(client.as_usize() as u64), announced.metadata["client"].as_u64());
+        assert!(queued_events(&server.clients[client]).is_empty());
+    }
+
+    #[test]
+    fn should_reset_client_sending_first_without_default_subscription() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut server = listening_server(Config::default(), &mut event_loop);
+        let _peer = accept_client(&mut server, &mut event_loop);
+        let client = server.clients.iter().next().unwrap().token;
+
+        server.handle_incoming_object(&mut event_loop, client, event("foo/bar"));
+        assert!(!server.clients.contains(client));
+        assert!(server.subscriptions.is_empty());
+    }
+
+    #[test]
+    fn should_give_default_subscription_to_client_with_bad_first_subscription() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let config = Config { default_subscription: config_subscription(&["@bar/*"]), ..Config::default() };
+        let mut server = listening_server(config, &mut event_loop);
+        let _watcher_peer = accept_client(&mut server, &mut event_loop);
+        let _client_peer = accept_client(&mut server, &mut event_loop);
+        let tokens: Vec<Token> = server.clients.iter().map(|client| client.token).collect();
+        let (watcher, client) = (tokens[0], tokens[1]);
+
+        server.handle_incoming_object(&mut event_loop, watcher, subscribe_object(&["@routing/*"]));
+        let bad = BusinessObject::builder().event("routing/subscribe").metadata("subscriptions", &1).build();
+        server.handle_incoming_object(&mut event_loop, client, Rc::new(bad));
+
+        assert_eq!(config_subscription(&["@bar/*"]), server.clients[client].subscription);
+        assert_eq!(vec!["routing/subscribe/reply"], queued_events(&server.clients[client]));
+        let reply = &server.clients[client].send_queue[0].object;
+        assert!(reply.metadata.contains_key("error"));
+        assert_eq!(vec!["routing/subscribe/reply", "routing/announcement", "routing/announcement"],
+                   queued_events(&server.clients[watcher]));
+    }
+
+    #[test]
+    fn should_reset_client_with_bad_first_subscription_without_default_subscription() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut server = listening_server(Config::default(), &mut event_loop);
+        let _peer = accept_client(&mut server, &mut event_loop);
+        let client = server.clients.iter().next().unwrap().token;
+
+        let bad = BusinessObject::builder().event("routing/subscribe").metadata("subscriptions", &1).build();
+        server.handle_incoming_object(&mut event_loop, client, Rc::new(bad));
+        assert!(!server.clients.contains(client));
+    }
+
+    #[test]
+    fn should_replace_subscription_on_resubscribe() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut server = Server::new(Config::default(), Vec::new());
+        let (watcher, _watcher_peer) = add_client(&mut server);
+        let (client, _client_peer) = add_client(&mut server);
+        server.clients[watcher].register(&mut event_loop).unwrap();
+        server.clients[client].register(&mut event_loop).unwrap();
+
+        server.handle_incoming_object(&mut event_loop, watcher, subscribe_object(&["@routing/*"]));
+        server.handle_incoming_object(&mut event_loop, client, subscribe_object(&["@foo/*"]));
+        server.handle_incoming_object(&mut event_loop, watcher, event("foo/bar"));
+        assert_eq!(vec!["routing/subscribe/reply", "foo/bar"], queued_events(&server.clients[client]));
+
+        server.handle_incoming_object(&mut event_loop, client, subscribe_object(&["@bar/*"]));
+        server.handle_incoming_object(&mut event_loop, watcher, event("foo/bar"));
+        server.handle_incoming_object(&mut event_loop, watcher, event("bar/foo"));
+        assert_eq!(vec!["routing/subscribe/reply", "foo/bar", "routing/subscribe/reply", "bar/foo"],
+                   queued_events(&server.clients[client]));
+
+        let changes: Vec<String> = server.clients[watcher].send_queue.iter()
+            .filter_map(|object| object.object.metadata.get("change").and_then(|change| change.as_string()))
+            .map(String::from)
+            .collect();
+        assert_eq!(vec!["subscribed", "subscribed", "resubscribed"], changes);
+
+        server.reset_connection(&mut event_loop, client);
+        assert_eq!(1, server.subscriptions.len());
+    }
+
+    #[test]
+    fn should_keep_subscription_when_resubscribe_is_bad() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut server = Server::new(Config::default(), Vec::new());
+        let (client, _peer) = add_client(&mut server);
+        server.clients[client].register(&mut event_loop).unwrap();
+
+        server.handle_incoming_object(&mut event_loop, client, subscribe_object(&["@foo/*"]));
+        let bad = BusinessObject::builder().event("routing/subscribe").metadata("subscriptions", &1).build();
+        server.handle_incoming_object(&mut event_loop, client, Rc::new(bad));
+
+        let reply = &server.clients[client].send_queue[1].object;
+        assert_eq!(Some("routing/subscribe/reply".to_string()), reply.event);
+        assert!(reply.metadata.contains_key("error"));
+        assert_eq!(Some(BusinessSubscription::List(vec![BusinessSubscription::String("@foo/*".to_string())])),
+                   server.clients[client].subscription);
+    }
+
+    #[test]
+    fn should_disconnect_slow_consumer_overflowing_with_subscribe_reply() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut config = Config::default();
+        config.queue.max_objects = Some(1);
+        let mut server = Server::new(config, Vec::new());
+        let (token, _peer) = add_client(&mut server);
+        server.clients[token].register(&mut event_loop).unwrap();
+
+        server.handle_incoming_object(&mut event_loop, token, subscribe_object(&["@foo/*"]));
+        assert_eq!(vec!["routing/subscribe/reply"], queued_events(&server.clients[token]));
+
+        server.handle_incoming_object(&mut event_loop, token, subscribe_object(&["@bar/*"]));
+        assert!(!server.clients.contains(token));
+        assert!(server.subscriptions.is_empty());
+    }
+
+    #[test]
+    fn should_answer_ping_only_when_subscribed_to_pong() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut server = Server::new(Config::default(), Vec::new());
+        let (client, _peer) = add_client(&mut server);
+        let (other, _other_peer) = add_client(&mut server);
+        server.clients[client].register(&mut event_loop).unwrap();
+        server.clients[other].register(&mut event_loop).unwrap();
+
+        server.handle_incoming_object(&mut event_loop, client, subscribe_object(&["@pong"]));
+        server.handle_incoming_object(&mut event_loop, other, subscribe_object(&["@foo/*"]));
+        server.handle_incoming_object(&mut event_loop, client, event("ping"));
+        server.handle_incoming_object(&mut event_loop, other, event("ping"));
+
+        assert_eq!(vec!["routing/subscribe/reply", "pong"], queued_events(&server.clients[client]));
+        assert_eq!(vec!["routing/subscribe/reply"], queued_events(&server.clients[other]));
+    }
+
+    #[test]
+    fn should_send_queued_objects_in_order() {
+        let (mut client, peer) = connected_client();
+        let events: Vec<String> = (0 .. 20).map(|i| format!("test/{}", i)).collect();
+
+        for event in events.iter() {
+            client.send_object(outgoing(event)).unwrap();
+        }
+        while !client.send_queue.is_empty() {
+            client.writable().unwrap();
+        }
+
+        let mut stream = BusinessObjectStream::new(peer);
+        let mut received = Vec::new();
+        while received.len() < events.len() {
+            received.extend(stream.read_business_objects().unwrap());
+        }
+
+        let received: Vec<String> = received.into_iter().map(|obj| obj.event.unwrap()).collect();
+        assert_eq!(events, received);
+    }
+}
@@ -0,0 +1,323 @@
+//! The sockets rabboe listens on and talks to clients through. Clients come
+//! in over TCP or over a Unix domain socket; once accepted, both kinds are
+//! handled alike.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, IoSlice, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net as std_unix;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use mio::{EventSet, Evented, PollOpt, Selector, Token};
+use mio::tcp::{TcpListener, TcpStream};
+use mio::unix::{UnixListener, UnixStream};
+
+
+/// Where to listen for clients: `host:port` for TCP, `unix:PATH` for a Unix
+/// domain socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf)
+}
+
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ListenAddr, String> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!("No socket path in {:?}", s));
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+
+        SocketAddr::from_str(s)
+            .map(ListenAddr::Tcp)
+            .map_err(|e| format!("Invalid listen address {:?}: {}", s, e))
+    }
+}
+
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ListenAddr::Tcp(ref addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(ref path) => write!(f, "unix:{}", path.display())
+        }
+    }
+}
+
+
+/// The address of a connected client. Clients of a Unix domain socket have
+/// no address of their own and are known by the socket they connected to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf)
+}
+
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PeerAddr::Tcp(ref addr) => write!(f, "{}", addr),
+            PeerAddr::Unix(ref path) => write!(f, "unix:{}", path.display())
+        }
+    }
+}
+
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix { listener: UnixListener, path: PathBuf }
+}
+
+
+impl Listener {
+    /// Binds to `addr`. A socket file left behind at a Unix socket path is
+    /// removed first, unless something still accepts connections on it.
+    /// The socket file gets the permissions `unix_mode`, if given, and is
+    /// otherwise left to the umask.
+    pub fn bind(addr: &ListenAddr, unix_mode: Option<u32>) -> io::Result<Listener> {
+        match *addr {
+            ListenAddr::Tcp(ref addr) => TcpListener::bind(addr).map(Listener::Tcp),
+            ListenAddr::Unix(ref path) => {
+                remove_stale_socket(path)?;
+                // Dropped on failure, which removes the socket file again.
+                let listener = Listener::Unix { listener: UnixListener::bind(path)?, path: path.clone() };
+                if let Some(mode) = unix_mode {
+                    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+                }
+                Ok(listener)
+            }
+        }
+    }
+
+    /// Accepts a client, if one is waiting.
+    pub fn accept(&self) -> io::Result<Option<(ClientStream, PeerAddr)>> {
+        match *self {
+            Listener::Tcp(ref listener) => match listener.accept()? {
+                Some(stream) => {
+                    let addr = stream.peer_addr()?;
+                    Ok(Some((ClientStream::Tcp(stream), PeerAddr::Tcp(addr))))
+                },
+                None => Ok(None)
+            },
+            Listener::Unix { ref listener, ref path } => Ok(listener.accept()?.map(|stream| {
+                (ClientStream::Unix(stream), PeerAddr::Unix(path.clone()))
+            }))
+        }
+    }
+
+    pub fn addr(&self) -> io::Result<ListenAddr> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.local_addr().map(ListenAddr::Tcp),
+            Listener::Unix { ref path, .. } => Ok(ListenAddr::Unix(path.clone()))
+        }
+    }
+
+    fn evented(&self) -> &dyn Evented {
+        match *self {
+            Listener::Tcp(ref listener) => listener,
+            Listener::Unix { ref listener, .. } => listener
+        }
+    }
+}
+
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix { ref path, .. } = *self {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Couldn't remove socket {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+
+impl Evented for Listener {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.evented().register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.evented().reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.evented().deregister(selector)
+    }
+}
+
+
+fn remove_stale_socket(path: &PathBuf) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.file_type().is_socket() => {
+            if std_unix::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                          format!("{} is in use", path.display())));
+            }
+            info!("Removing stale socket {}", path.display());
+            fs::remove_file(path)
+        },
+        _ => Ok(())
+    }
+}
+
+
+pub enum ClientStream {
+    Tcp(TcpStream),
+    Unix(UnixStream)
+}
+
+
+impl ClientStream {
+    fn evented(&self) -> &dyn Evented {
+        match *self {
+            ClientStream::Tcp(ref stream) => stream,
+            ClientStream::Unix(ref stream) => stream
+        }
+    }
+}
+
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            ClientStream::Tcp(ref mut stream) => stream.read(buf),
+            ClientStream::Unix(ref mut stream) => stream.read(buf)
+        }
+    }
+}
+
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            ClientStream::Tcp(ref mut stream) => stream.write(buf),
+            ClientStream::Unix(ref mut stream) => stream.write(buf)
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        match *self {
+            ClientStream::Tcp(ref mut stream) => stream.write_vectored(bufs),
+            ClientStream::Unix(ref mut stream) => stream.write_vectored(bufs)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            ClientStream::Tcp(ref mut stream) => stream.flush(),
+            ClientStream::Unix(ref mut stream) => stream.flush()
+        }
+    }
+}
+
+
+impl Evented for ClientStream {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.evented().register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.evented().reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.evented().deregister(selector)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net as std_unix;
+    use std::path::PathBuf;
+    use std::process;
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{remove_stale_socket, ListenAddr, Listener, PeerAddr};
+
+    /// A socket path of its own for each test.
+    fn socket_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("rabboe-{}-{}.sock", process::id(), name))
+    }
+
+    #[test]
+    fn should_parse_listen_addresses() {
+        assert_eq!(ListenAddr::Tcp(FromStr::from_str("127.0.0.1:7890").unwrap()),
+                   ListenAddr::from_str("127.0.0.1:7890").unwrap());
+        assert_eq!(ListenAddr::Unix(PathBuf::from("/run/rabboe.sock")),
+                   ListenAddr::from_str("unix:/run/rabboe.sock").unwrap());
+        assert!(ListenAddr::from_str("unix:").is_err());
+        assert!(ListenAddr::from_str("localhost").is_err());
+    }
+
+    #[test]
+    fn should_accept_clients_on_unix_socket() {
+        let path = socket_path("accept");
+        let listener = Listener::bind(&ListenAddr::Unix(path.clone()), None).unwrap();
+        assert_eq!(ListenAddr::Unix(path.clone()), listener.addr().unwrap());
+
+        let _client = std_unix::UnixStream::connect(&path).unwrap();
+        loop {
+            if let Some((_, peer_addr)) = listener.accept().unwrap() {
+                assert_eq!(PeerAddr::Unix(path.clone()), peer_addr);
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn should_set_unix_socket_mode() {
+        let path = socket_path("mode");
+        let _listener = Listener::bind(&ListenAddr::Unix(path.clone()), Some(0o600)).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o7777);
+    }
+
+    #[test]
+    fn should_not_remove_socket_in_use() {
+        let path = socket_path("in-use");
+        let _listener = Listener::bind(&ListenAddr::Unix(path.clone()), None).unwrap();
+
+        assert_eq!(io::ErrorKind::AddrInUse, remove_stale_socket(&path).unwrap_err().kind());
+        assert!(Listener::bind(&ListenAddr::Unix(path.clone()), None).is_err());
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn should_replace_stale_socket() {
+        let path = socket_path("stale");
+        drop(std_unix::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let _listener = Listener::bind(&ListenAddr::Unix(path.clone()), None).unwrap();
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn should_remove_socket_when_dropped() {
+        let path = socket_path("drop");
+        let listener = Listener::bind(&ListenAddr::Unix(path.clone()), None).unwrap();
+        assert!(path.exists());
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+}
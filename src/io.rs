@@ -1,216 +1,645 @@
-use std::io::{Read, Write};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::{IoSlice, Read, Write};
 use std::io;
-use std::net as std_net;
+use std::mem;
+use std::str;
+use std::sync::Arc;
 
-use mio::tcp as mio_tcp;
+use rustc_serialize::json::{self, Json, ToJson};
 
-use rustc_serialize::json::{Json};
+use ::object::{BusinessObject, Payload, ReadBusinessObjectError, SerializeBusinessObjectError};
 
-use ::object::{BusinessObject, Payload, ReadBusinessObjectError};
 
+const NUL: u8 = b'\0';
+/// How much a `BusinessObjectStream` reads at most at a time by default.
+pub const READ_BUF_SIZE: usize = 1024 * 1024;
 
-const NUL: u8 = '\0' as u8;
-const READ_BUF_SIZE: usize = 1024 * 1024;
+
+/// The outcome of decoding one frame.
+pub type FrameResult = Result<BusinessObject, ReadBusinessObjectError>;
 
 
 pub trait ReadBusinessObject {
+    /// Reads and decodes the available objects, stopping at the first frame
+    /// that can't be decoded. The bad frame is not consumed, so once the
+    /// objects before it have been returned the stream keeps failing.
     fn read_business_objects(&mut self) -> Result<Vec<BusinessObject>, ReadBusinessObjectError>;
+
+    /// Reads and decodes the available frames, recovering from bad ones.
+    ///
+    /// A frame whose header can't be decoded is reported as an `Err` in
+    /// its place and skipped up to the NUL ending its header; decoding
+    /// carries on with the next frame. Only errors reading from the
+    /// underlying stream fail the whole call.
+    fn read_frames(&mut self) -> Result<Vec<FrameResult>, ReadBusinessObjectError>;
+}
+
+
+pub trait WriteBusinessObject {
+    fn write_business_object(&mut self, object: &BusinessObject) -> Result<usize, SerializeBusinessObjectError>;
 }
 
 
-pub struct BusinessObjectStream<S: Read + Write> {
+/// Frames business objects over any byte stream: a socket, a pipe, stdin and
+/// stdout, an in-memory `Cursor` or a TLS session. Reading needs `S: Read`
+/// and writing `S: Write`.
+pub struct BusinessObjectStream<S> {
+    decoder: BusinessObjectDecoder,
+    read_buffer_size: usize,
+    /// Reused for every read; allocated on the first one.
     read_buffer: Vec<u8>,
     pub socket: S,
 }
 
 
-impl <S: Read + Write> BusinessObjectStream<S> {
+impl <S> BusinessObjectStream<S> {
     pub fn new(socket: S) -> BusinessObjectStream<S> {
+        BusinessObjectStream::with_limits(socket, DecoderLimits::default())
+    }
+
+    pub fn with_limits(socket: S, limits: DecoderLimits) -> BusinessObjectStream<S> {
         BusinessObjectStream {
+            decoder: BusinessObjectDecoder::with_limits(limits),
+            read_buffer_size: READ_BUF_SIZE,
             read_buffer: Vec::new(),
-            socket: socket,
+            socket,
         }
     }
+
+    /// Sets how much is read from the socket at most at a time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn set_read_buffer_size(&mut self, size: usize) {
+        assert!(size > 0, "Read buffer size must not be zero");
+        self.read_buffer_size = size;
+        self.read_buffer = Vec::new();
+    }
 }
 
 
-impl Write for BusinessObjectStream<mio_tcp::TcpStream> {
+impl <S: Write> Write for BusinessObjectStream<S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.socket.write(buf)
     }
 
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.socket.write_vectored(bufs)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         self.socket.flush()
     }
 }
 
 
-impl Write for BusinessObjectStream<std_net::TcpStream> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.socket.write(buf)
+/// Encodes the metadata header of `object`, including the terminating NUL.
+///
+/// The metadata is encoded straight from the object without building an
+/// intermediate `Json` tree of it.
+pub fn encode_header(object: &BusinessObject) -> Result<Vec<u8>, SerializeBusinessObjectError> {
+    let event = object.event.as_ref().map(|event| event.to_json());
+    let _type = object._type.as_ref().map(|_type| _type.to_json());
+    let size = object.size.map(|size| size.to_json());
+
+    let mut fields: BTreeMap<&str, &Json> = BTreeMap::new();
+    for (key, value) in object.metadata.iter() {
+        fields.insert(key, value);
     }
+    if let Some(ref _type) = _type { fields.insert("type", _type); }
+    if let Some(ref size) = size { fields.insert("size", size); }
+    if let Some(ref event) = event { fields.insert("event", event); }
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.socket.flush()
+    let mut header = Vec::new();
+    write!(header, "{}", json::as_json(&fields))?;
+    header.push(NUL);
+
+    Ok(header)
+}
+
+
+/// A business object encoded for the wire: its header and its payload.
+///
+/// The header and the payload are written one after the other (vectored
+/// where the writer supports it) and never joined into one buffer. A frame
+/// borrows the payload of its object; one that is encoded once and kept for
+/// many recipients is made independent of the object with `into_shared`.
+#[derive(Debug, Clone)]
+pub struct Frame<'a> {
+    header: Vec<u8>,
+    payload: Option<Cow<'a, Payload>>
+}
+
+
+impl <'a> Frame<'a> {
+    pub fn new(object: &'a BusinessObject) -> Result<Frame<'a>, SerializeBusinessObjectError> {
+        object.checked_payload()?;
+
+        Ok(Frame {
+            header: encode_header(object)?,
+            payload: object.payload.as_ref().map(Cow::Borrowed)
+        })
+    }
+
+    /// Turns the frame into one owning its payload in shared form, so that
+    /// it can outlive the object and be cloned without copying the payload.
+    /// Shared payloads, which is what the decoder produces, aren't copied
+    /// here either; `Payload::Bytes` are copied once.
+    pub fn into_shared(self) -> Frame<'static> {
+        let payload = self.payload.map(|payload| match payload {
+            Cow::Borrowed(Payload::Bytes(bytes)) => Payload::Shared(Arc::from(&bytes[..])),
+            Cow::Borrowed(shared) => shared.clone(),
+            Cow::Owned(payload) => payload.into_shared()
+        });
+
+        Frame {
+            header: self.header,
+            payload: payload.map(Cow::Owned)
+        }
+    }
+
+    pub fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        match self.payload {
+            Some(ref payload) => payload.as_bytes(),
+            None => &[]
+        }
+    }
+
+    /// Length of the whole frame on the wire.
+    pub fn len(&self) -> usize {
+        self.header.len() + self.payload().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes as much of the frame, starting `offset` bytes into it, as the
+    /// writer accepts, returning the number of bytes written.
+    ///
+    /// Writes are repeated until the frame is done, so fewer bytes than the
+    /// rest of the frame are only written when the writer fails part way, for
+    /// example with `WouldBlock` on a congested non-blocking socket. The
+    /// error itself is then left for the next call to report.
+    pub fn write_from<W: Write>(&self, writer: &mut W, offset: usize) -> io::Result<usize> {
+        let mut written = 0;
+
+        while offset + written < self.len() {
+            match self.write_once(writer, offset + written) {
+                Ok(0) if written == 0 => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole frame"));
+                },
+                Ok(0) => { break; },
+                Ok(n) => { written += n; },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) if written > 0 => { break; },
+                Err(e) => { return Err(e); }
+            }
+        }
+
+        Ok(written)
+    }
+
+    /// Writes the whole frame.
+    pub fn write_all<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut offset = 0;
+
+        while offset < self.len() {
+            offset += self.write_from(writer, offset)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_once<W: Write>(&self, writer: &mut W, offset: usize) -> io::Result<usize> {
+        if offset < self.header.len() {
+            let bufs = [IoSlice::new(&self.header[offset ..]), IoSlice::new(self.payload())];
+            writer.write_vectored(&bufs)
+        } else {
+            writer.write(&self.payload()[offset - self.header.len() ..])
+        }
     }
 }
 
 
-fn parse_one_object(buffer: &[u8]) -> Result<BusinessObject, ReadBusinessObjectError> {
-    let mut vec: Vec<u8> = Vec::with_capacity(buffer.len());
-    vec.extend(buffer);
+impl <W: Write> WriteBusinessObject for W {
+    fn write_business_object(&mut self, object: &BusinessObject) -> Result<usize, SerializeBusinessObjectError> {
+        let frame = Frame::new(object)?;
+        frame.write_all(self)?;
+        Ok(frame.len())
+    }
+}
 
-    match String::from_utf8(vec) {
-        Ok(utf8_string) => match Json::from_str(&utf8_string) {
+
+fn parse_one_object(buffer: &[u8]) -> Result<BusinessObject, ReadBusinessObjectError> {
+    match str::from_utf8(buffer) {
+        Ok(utf8_string) => match Json::from_str(utf8_string) {
             Ok(json_obj) => BusinessObject::from_json(&json_obj),
-            Err(e) => Err(ReadBusinessObjectError::JsonSyntaxError(
-                format!("{}", e), utf8_string))
+            Err(e) => Err(ReadBusinessObjectError::JsonSyntaxError(e))
         },
-        Err(_) => Err(ReadBusinessObjectError::BufferCharacterDecodingError)
+        Err(e) => Err(ReadBusinessObjectError::BufferCharacterDecodingError(e))
     }
 }
 
-enum ReadOneResult {
-    Ok(BusinessObject, usize),
-    NoNull,
-    NotEnoughInput,
-    NotEnoughPayloadInput,
-    Error(ReadBusinessObjectError)
+
+/// How far into a stream decoding has got.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct StreamPosition {
+    /// Bytes consumed from the start of the stream.
+    offset: u64,
+    /// Frames decoded from the start of the stream.
+    frame: u64
+}
+
+
+/// Bounds on what a peer can make a decoder buffer. `None` means unbounded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecoderLimits {
+    /// Longest header accepted, not counting its NUL.
+    pub max_header_len: Option<usize>,
+    /// Largest `size` a header may declare.
+    pub max_payload_size: Option<usize>,
+    /// Most bytes buffered at any time. This has to leave room for a whole
+    /// frame of the largest accepted header and payload.
+    pub max_buffered: Option<usize>
+}
+
+
+#[derive(Debug)]
+enum DecoderState {
+    /// Looking for the NUL that ends the next header.
+    AwaitingHeader,
+    /// The header of `object` has been decoded; waiting for `size` bytes of
+    /// its payload.
+    AwaitingPayload { object: BusinessObject, size: usize },
+    /// A limit was exceeded. Input is discarded from here on.
+    Failed
 }
 
 
-fn read_one_object(buffer:&[u8]) -> ReadOneResult {
-    let nul_position = buffer.iter().position(|item| item == &NUL);
+/// Incremental decoder for a stream of business object frames.
+///
+/// Bytes are fed in as they arrive, in pieces of any size; `decode` and
+/// `decode_strict` return the frames completed so far and keep partial
+/// headers and payloads buffered until the rest of them arrives.
+///
+/// Exceeding one of the `DecoderLimits` is reported once, after which the
+/// decoder drops its input and decodes nothing more.
+#[derive(Debug)]
+pub struct BusinessObjectDecoder {
+    limits: DecoderLimits,
+    buffer: Vec<u8>,
+    /// Start of the undecoded input in `buffer`.
+    start: usize,
+    /// How much of the undecoded input is known not to contain a NUL.
+    scanned: usize,
+    /// Stream position of `buffer[start]`.
+    position: StreamPosition,
+    state: DecoderState,
+    /// A limit error met after the objects `decode_strict` last returned,
+    /// kept for its next call.
+    pending_error: Option<ReadBusinessObjectError>
+}
 
-    if nul_position.is_none() {
-        return ReadOneResult::NoNull;
+
+impl Default for BusinessObjectDecoder {
+    fn default() -> BusinessObjectDecoder {
+        BusinessObjectDecoder::new()
     }
-    let nul_pos = nul_position.unwrap();
+}
 
-    let metadata_part: &[u8] = &buffer[0 .. nul_pos];
 
-    if metadata_part.len() == 0 {
-        return ReadOneResult::NotEnoughInput;
+impl BusinessObjectDecoder {
+    pub fn new() -> BusinessObjectDecoder {
+        BusinessObjectDecoder::with_limits(DecoderLimits::default())
     }
 
-    // println!("metadata_part: {:?}", metadata_part);
-    match parse_one_object(metadata_part) {
-        Ok(obj) => {
-            if obj.has_payload() {
-                // println!("buf: {:?}", buffer);
-                let payload_part: &[u8] = &buffer[nul_pos + 1 .. buffer.len()];
-                // println!("payload_part: {:?}", payload_part);
-                if obj.size.unwrap() > payload_part.len() {
-                    debug!("Not enough input for size {}", payload_part.len());
-                    return ReadOneResult::NotEnoughPayloadInput;
-                }
+    pub fn with_limits(limits: DecoderLimits) -> BusinessObjectDecoder {
+        BusinessObjectDecoder {
+            limits,
+            buffer: Vec::new(),
+            start: 0,
+            scanned: 0,
+            position: StreamPosition::default(),
+            state: DecoderState::AwaitingHeader,
+            pending_error: None
+        }
+    }
 
-                let size = obj.size.unwrap();
-                let mut payload_vec: Vec<u8> = Vec::with_capacity(size);
-                for item in buffer[nul_pos + 1 ..  nul_pos + 1 + size].iter() {
-                    payload_vec.push(item.clone());
-                }
+    pub fn set_limits(&mut self, limits: DecoderLimits) {
+        self.limits = limits;
+    }
 
-                let result = BusinessObject { payload: Some(Payload::Bytes(payload_vec)),
-                                              .. obj };
-                ReadOneResult::Ok(result, nul_pos + 1 + size)
-            } else {
-                ReadOneResult::Ok(obj, nul_pos + 1)
+    /// Buffers `bytes` for decoding, unless that would take the buffer past
+    /// `max_buffered`.
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<(), ReadBusinessObjectError> {
+        if let DecoderState::Failed = self.state {
+            return Ok(());
+        }
+
+        let buffered = self.buffered() + bytes.len();
+        match self.limits.max_buffered {
+            Some(limit) if buffered > limit => {
+                self.fail();
+                Err(ReadBusinessObjectError::BufferLimitExceeded { buffered, limit })
+            },
+            _ => {
+                self.buffer.extend_from_slice(bytes);
+                Ok(())
             }
-        },
-        Err(e) => ReadOneResult::Error(e)
+        }
     }
-}
 
+    /// Whether a limit has been exceeded.
+    pub fn has_failed(&self) -> bool {
+        matches!(self.state, DecoderState::Failed)
+    }
 
-fn read_objects(buffer: &[u8]) -> Result<(Vec<BusinessObject>, usize), ReadBusinessObjectError> {
-    let mut result = Vec::new();
+    /// Number of bytes fed in but not yet consumed by a decoded frame.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() - self.start
+    }
 
-    let mut start = 0;
-    loop {
-        // println!("start: {:?}", start);
-        match read_one_object(&buffer[start .. buffer.len()]) {
-            ReadOneResult::Ok(obj, consumed) => {
-                result.push(obj);
-                start += consumed;
-            },
-            ReadOneResult::NoNull => {
-            },
-            ReadOneResult::Error(e) => {
-                return Err(e);
-            },
-            ReadOneResult::NotEnoughInput => {
-                break;
-            },
-            ReadOneResult::NotEnoughPayloadInput => {
+    /// Number of bytes that can still be fed in without exceeding
+    /// `max_buffered`, or `None` if buffering is unbounded.
+    pub fn room(&self) -> Option<usize> {
+        self.limits.max_buffered.map(|limit| limit.saturating_sub(self.buffered()))
+    }
+
+    /// Decodes the complete frames in the input, recovering from bad ones.
+    ///
+    /// A frame whose header can't be decoded is reported as an `Err` in its
+    /// place and skipped up to the NUL ending its header.
+    pub fn decode(&mut self) -> Vec<FrameResult> {
+        let mut result = Vec::new();
+
+        while let Some(frame) = self.next_frame(true) {
+            result.push(frame);
+        }
+
+        self.compact();
+        result
+    }
+
+    /// Decodes the complete frames in the input up to the first bad one.
+    ///
+    /// Objects before a bad frame are returned first; the bad frame is not
+    /// consumed, so the next call fails with its error, as do all calls
+    /// after that.
+    pub fn decode_strict(&mut self) -> Result<Vec<BusinessObject>, ReadBusinessObjectError> {
+        if let Some(e) = self.pending_error.take() {
+            return Err(e);
+        }
+
+        let mut result = Vec::new();
+
+        while let Some(frame) = self.next_frame(false) {
+            match frame {
+                Ok(object) => { result.push(object); },
+                Err(e) => {
+                    if result.is_empty() {
+                        return Err(e);
+                    }
+                    // A bad frame is met again by the next call, but a
+                    // failed decoder has dropped its input with it.
+                    if self.has_failed() {
+                        self.pending_error = Some(e);
+                    }
+                    break;
+                }
             }
         }
 
-        if start == buffer.len() {
-            break;
+        self.compact();
+        Ok(result)
+    }
+
+    /// Steps the state machine until it completes a frame or needs more
+    /// input.
+    fn next_frame(&mut self, resynchronize: bool) -> Option<FrameResult> {
+        loop {
+            let available = self.buffer.len() - self.start;
+
+            match self.state {
+                DecoderState::Failed => {
+                    return None;
+                },
+                DecoderState::AwaitingHeader => {
+                    let nul_pos = self.buffer[self.start + self.scanned ..].iter().position(|&byte| byte == NUL);
+                    let header_len = match nul_pos {
+                        Some(pos) => self.scanned + pos,
+                        None => available
+                    };
+
+                    if let Some(limit) = self.limits.max_header_len {
+                        if header_len > limit {
+                            let error = ReadBusinessObjectError::HeaderTooLong { limit };
+                            return Some(Err(self.fail_in_frame(error, header_len)));
+                        }
+                    }
+
+                    let nul_pos = match nul_pos {
+                        Some(_) => header_len,
+                        None => {
+                            self.scanned = available;
+                            return None;
+                        }
+                    };
+
+                    let parsed = parse_one_object(&self.buffer[self.start .. self.start + nul_pos]);
+                    match parsed {
+                        Ok(object) => {
+                            if let (Some(size), Some(limit)) = (object.size, self.limits.max_payload_size) {
+                                if size > limit {
+                                    let error = ReadBusinessObjectError::PayloadTooLarge { size, limit };
+                                    return Some(Err(self.fail_in_frame(error, nul_pos)));
+                                }
+                            }
+
+                            self.advance(nul_pos + 1);
+
+                            match object.size {
+                                Some(size) if object.has_payload() => {
+                                    self.state = DecoderState::AwaitingPayload { object, size };
+                                },
+                                _ => {
+                                    self.position.frame += 1;
+                                    return Some(Ok(object));
+                                }
+                            }
+                        },
+                        Err(e) => {
+                            let header = &self.buffer[self.start .. self.start + nul_pos];
+                            let e = e.in_frame(self.position.offset, self.position.frame, header);
+
+                            if resynchronize {
+                                self.advance(nul_pos + 1);
+                                self.position.frame += 1;
+                            }
+
+                            return Some(Err(e));
+                        }
+                    }
+                },
+                DecoderState::AwaitingPayload { size, .. } => {
+                    if available < size {
+                        debug!("Not enough input for size {}, have {}", size, available);
+                        return None;
+                    }
+
+                    let payload = Payload::Shared(Arc::from(&self.buffer[self.start .. self.start + size]));
+                    self.advance(size);
+                    self.position.frame += 1;
+
+                    match mem::replace(&mut self.state, DecoderState::AwaitingHeader) {
+                        DecoderState::AwaitingPayload { object, .. } => {
+                            return Some(Ok(BusinessObject { payload: Some(payload), .. object }));
+                        },
+                        _ => unreachable!()
+                    }
+                }
+            }
         }
     }
 
-    Ok((result, start))
+    fn advance(&mut self, consumed: usize) {
+        self.start += consumed;
+        self.scanned = 0;
+        self.position.offset += consumed as u64;
+    }
+
+    /// Wraps `error` with the position and header of the current frame and
+    /// puts the decoder into its failed state.
+    fn fail_in_frame(&mut self, error: ReadBusinessObjectError, header_len: usize) -> ReadBusinessObjectError {
+        let header = &self.buffer[self.start .. self.start + header_len];
+        let error = error.in_frame(self.position.offset, self.position.frame, header);
+        self.fail();
+        error
+    }
+
+    fn fail(&mut self) {
+        self.state = DecoderState::Failed;
+        self.buffer = Vec::new();
+        self.start = 0;
+        self.scanned = 0;
+    }
+
+    /// Drops consumed input from the front of the buffer.
+    fn compact(&mut self) {
+        self.buffer.drain(.. self.start);
+        self.start = 0;
+    }
 }
 
 
-impl <S: Read + Write> ReadBusinessObject for BusinessObjectStream<S> {
-    fn read_business_objects(&mut self) -> Result<Vec<BusinessObject>, ReadBusinessObjectError> {
-        let mut read_buf = [0; READ_BUF_SIZE];
+impl <S: Read> BusinessObjectStream<S> {
+    fn fill_read_buffer(&mut self) -> Result<(), ReadBusinessObjectError> {
+        // Read no more than the decoder has room for, so that frames are
+        // decoded before more input is buffered. With no room left, the
+        // buffered frame is incomplete and one more byte exceeds the limit.
+        let size = match self.decoder.room() {
+            Some(room) => room.clamp(1, self.read_buffer_size),
+            None => self.read_buffer_size
+        };
+        if self.read_buffer.len() < size {
+            self.read_buffer.resize(size, 0);
+        }
 
-        match self.socket.read(&mut read_buf) {
+        match self.socket.read(&mut self.read_buffer[.. size]) {
             Ok(0) => {
                 warn!("Likely can't read from this socket any more!");
             },
             Ok(bytes_read) => {
-                // println!("Bytes read: {}", bytes_read);
-                for item in read_buf[0 .. bytes_read].iter() {
-                    self.read_buffer.push(*item);
-                }
+                self.decoder.feed(&self.read_buffer[.. bytes_read])?;
             },
             Err(e) => {
                 return Err(ReadBusinessObjectError::ReadError(e));
             }
         };
 
-        match read_objects(&self.read_buffer) {
-            Ok((objects, consumed)) => {
-                let mut new_buffer: Vec<u8> = Vec::new();
+        Ok(())
+    }
+}
 
-                for item in self.read_buffer[consumed .. ].iter() {
-                    new_buffer.push(item.clone());
-                }
 
-                self.read_buffer = new_buffer;
+impl <S: Read> ReadBusinessObject for BusinessObjectStream<S> {
+    fn read_business_objects(&mut self) -> Result<Vec<BusinessObject>, ReadBusinessObjectError> {
+        self.fill_read_buffer()?;
+        self.decoder.decode_strict()
+    }
 
-                Ok(objects)
-            },
-            Err(e) => Err(e)
-        }
+    fn read_frames(&mut self) -> Result<Vec<FrameResult>, ReadBusinessObjectError> {
+        self.fill_read_buffer()?;
+        Ok(self.decoder.decode())
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use ::super::{read_objects, NUL};
-    use ::object::{BusinessObject, Payload};
+    use std::io;
+    use std::io::Write;
 
+    use rustc_serialize::json::ToJson;
 
-    fn nth_parsed_object (buffer: &Vec<u8>, index: usize) -> BusinessObject {
-        let objs_result = read_objects(&buffer);
-        
-        match objs_result {
-            Err(e) => {
-                println!("{:?}", e);
-                panic!()
-            },
-            _ => {}
+    use super::{encode_header, BusinessObjectDecoder, BusinessObjectStream, DecoderLimits, Frame,
+                FrameResult, ReadBusinessObject, WriteBusinessObject, NUL};
+    use ::object::{BusinessObject, Payload, ReadBusinessObjectError};
+
+
+    /// Accepts at most `chunk` bytes per write, like a congested socket.
+    struct TrickleWriter {
+        written: Vec<u8>,
+        chunk: usize
+    }
+
+    impl Write for TrickleWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = ::std::cmp::min(self.chunk, buf.len());
+            self.written.extend(&buf[.. n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+
+    fn decode_all(buffer: &[u8]) -> Vec<FrameResult> {
+        let mut decoder = BusinessObjectDecoder::new();
+        decoder.feed(buffer).unwrap();
+        decoder.decode()
+    }
+
+    /// Feeds `buffer` to a decoder one byte at a time, decoding after each.
+    fn decode_bytewise(buffer: &[u8]) -> Vec<FrameResult> {
+        let mut decoder = BusinessObjectDecoder::new();
+        let mut result = Vec::new();
+
+        for byte in buffer {
+            decoder.feed(&[*byte]).unwrap();
+            result.extend(decoder.decode());
         }
 
-        let (objects, _) = objs_result.unwrap();
-        (*objects.get(index).unwrap()).clone()
+        assert_eq!(0, decoder.buffered());
+        result
+    }
+
+    fn nth_parsed_object (buffer: &[u8], index: usize) -> BusinessObject {
+        let mut decoder = BusinessObjectDecoder::new();
+        decoder.feed(buffer).unwrap();
+
+        match decoder.decode_strict() {
+            Ok(objects) => objects[index].clone(),
+            Err(e) => panic!("{}", e)
+        }
     }
 
     #[test]
@@ -252,11 +681,7 @@ mod tests {
         let obj = nth_parsed_object(&buf, 0);
         assert_eq!("foo/bar", obj.event.unwrap());
 
-        match obj.payload.unwrap() {
-            Payload::Bytes(bytes) => {
-                assert_eq!(payload, bytes);
-            }
-        }
+        assert_eq!(&payload[..], obj.payload.unwrap().as_bytes());
     }
 
     #[test]
@@ -276,19 +701,532 @@ mod tests {
         let obj = nth_parsed_object(&buf, 0);
         assert_eq!("foo/bar", obj.event.unwrap());
 
-        match obj.payload.unwrap() {
-            Payload::Bytes(bytes) => {
-                assert_eq!(payload1, bytes);
-            }
-        }
+        assert_eq!(&payload1[..], obj.payload.unwrap().as_bytes());
 
         let obj = nth_parsed_object(&buf, 1);
         assert_eq!("bar/foo", obj.event.unwrap());
 
-        match obj.payload.unwrap() {
-            Payload::Bytes(bytes) => {
-                assert_eq!(payload2, bytes);
+        assert_eq!(&payload2[..], obj.payload.unwrap().as_bytes());
+    }
+
+    #[test]
+    fn encode_header_should_match_json_representation() {
+        let obj = BusinessObject::builder()
+            .event("foo/bar")
+            .payload_type("text/plain")
+            .natures(vec!["hasselhoff"])
+            .metadata("id", "abc")
+            .payload("ABCDE")
+            .build();
+
+        let mut expected = obj.to_json().to_string().into_bytes();
+        expected.push(NUL);
+
+        assert_eq!(expected, encode_header(&obj).unwrap());
+    }
+
+    #[test]
+    fn frame_should_write_header_and_payload_in_pieces() {
+        let obj = BusinessObject::builder().event("foo/bar").payload("ABCDE").build();
+        let frame = Frame::new(&obj).unwrap();
+
+        let mut writer = TrickleWriter { written: Vec::new(), chunk: 3 };
+        assert_eq!(frame.len(), frame.write_from(&mut writer, 0).unwrap());
+        assert_eq!(obj.to_bytes(), writer.written);
+    }
+
+    #[test]
+    fn frame_should_resume_writing_after_would_block() {
+        /// Accepts `room` bytes, then would block until given more room.
+        struct CongestedWriter {
+            written: Vec<u8>,
+            room: usize
+        }
+
+        impl Write for CongestedWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.room == 0 {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "congested"));
+                }
+                let n = ::std::cmp::min(self.room, buf.len());
+                self.written.extend(&buf[.. n]);
+                self.room -= n;
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
             }
         }
+
+        let obj = BusinessObject::builder().event("foo/bar").payload("ABCDE").build();
+        let frame = Frame::new(&obj).unwrap();
+        let mut writer = CongestedWriter { written: Vec::new(), room: 5 };
+
+        let mut offset = frame.write_from(&mut writer, 0).unwrap();
+        assert_eq!(5, offset);
+        match frame.write_from(&mut writer, offset) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+            other => panic!("Unexpected result: {:?}", other)
+        }
+
+        while offset < frame.len() {
+            writer.room = 4;
+            offset += frame.write_from(&mut writer, offset).unwrap();
+        }
+        assert_eq!(obj.to_bytes(), writer.written);
+    }
+
+    #[test]
+    fn stream_should_work_over_in_memory_buffers() {
+        let first = BusinessObject::builder().event("foo/bar").payload("ABCDE").build();
+        let second = BusinessObject::builder().event("bar/foo").build();
+
+        let mut stream = BusinessObjectStream::new(io::Cursor::new(Vec::new()));
+        stream.write_business_object(&first).unwrap();
+        stream.write_business_object(&second).unwrap();
+
+        let written = stream.socket.into_inner();
+        let mut stream = BusinessObjectStream::new(io::Cursor::new(written));
+        let objects = stream.read_business_objects().unwrap();
+        assert_eq!(vec![first, second], objects);
+    }
+
+    #[test]
+    fn stream_should_read_at_most_read_buffer_size_at_a_time() {
+        let obj = BusinessObject::builder().event("foo/bar").payload("ABCDE").build();
+        let len = obj.to_bytes().len();
+
+        let mut stream = BusinessObjectStream::new(io::Cursor::new(obj.to_bytes()));
+        stream.set_read_buffer_size(len - 1);
+        assert!(stream.read_business_objects().unwrap().is_empty());
+        assert_eq!(vec![obj], stream.read_business_objects().unwrap());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn stream_should_work_over_unix_sockets() {
+        use std::os::unix::net::UnixStream;
+
+        let obj = BusinessObject::builder().event("foo/bar").payload(vec![NUL; 1000]).build();
+        let (left, right) = UnixStream::pair().unwrap();
+
+        let mut writer = BusinessObjectStream::new(left);
+        let mut reader = BusinessObjectStream::new(right);
+        writer.write_business_object(&obj).unwrap();
+
+        let mut objects = Vec::new();
+        while objects.is_empty() {
+            objects.extend(reader.read_business_objects().unwrap());
+        }
+        assert!(obj.equals_strict(&objects[0]));
+    }
+
+    #[test]
+    fn should_write_objects_that_read_back() {
+        let mut writer = TrickleWriter { written: Vec::new(), chunk: 7 };
+
+        let first = BusinessObject::builder()
+            .event("foo/bar")
+            .natures(vec!["hasselhoff"])
+            .metadata("id", "abc")
+            .payload("ABCDE")
+            .build();
+        let second = BusinessObject::builder().event("bar/foo").metadata("sequence", &2).build();
+        let written = writer.write_business_object(&first).unwrap()
+            + writer.write_business_object(&second).unwrap();
+
+        assert_eq!(writer.written.len(), written);
+
+        assert!(first.equals_strict(&nth_parsed_object(&writer.written, 0)));
+        assert!(second.equals_strict(&nth_parsed_object(&writer.written, 1)));
+    }
+
+    #[test]
+    fn frames_should_share_decoded_payloads() {
+        let mut buf = BusinessObject::builder().event("foo/bar").payload("ABCDE").build().to_bytes();
+        buf.extend(BusinessObject::builder().event("bar/foo").build().to_bytes());
+
+        let obj = nth_parsed_object(&buf, 0);
+        match obj.payload {
+            Some(Payload::Shared(_)) => {},
+            ref other => panic!("Expected a shared payload, got {:?}", other)
+        }
+
+        let frame = Frame::new(&obj).unwrap().into_shared();
+        let copy = frame.clone();
+        assert_eq!(obj.payload.as_ref().unwrap().as_bytes().as_ptr(), frame.payload().as_ptr());
+        assert_eq!(frame.payload().as_ptr(), copy.payload().as_ptr());
+        assert_eq!(obj.to_bytes().len(), frame.len());
+    }
+
+    #[test]
+    fn frame_should_borrow_owned_payload_until_shared() {
+        let obj = BusinessObject::builder().event("foo/bar").payload("ABCDE").build();
+        let payload = obj.payload.as_ref().unwrap().as_bytes();
+
+        let frame = Frame::new(&obj).unwrap();
+        assert_eq!(payload.as_ptr(), frame.payload().as_ptr());
+
+        let shared = frame.into_shared();
+        assert!(payload.as_ptr() != shared.payload().as_ptr());
+        assert_eq!(shared.payload().as_ptr(), shared.clone().payload().as_ptr());
+        assert_eq!(obj.to_bytes().len(), shared.len());
+    }
+
+    #[test]
+    fn should_report_position_and_header_of_bad_frames() {
+        let mut buf = BusinessObject::builder().event("foo/bar").payload("ABCDE").build().to_bytes();
+        let good_len = buf.len();
+        buf.extend(br#"{"event": "bar/foo""#.iter());
+        buf.push(NUL);
+
+        let mut decoder = BusinessObjectDecoder::new();
+        decoder.feed(&buf).unwrap();
+        assert_eq!(1, decoder.decode_strict().unwrap().len());
+        match decoder.decode_strict() {
+            Err(ReadBusinessObjectError::BadFrame(bad)) => {
+                assert_eq!(good_len as u64, bad.offset);
+                assert_eq!(1, bad.index);
+                assert_eq!(br#"{"event": "bar/foo""#.to_vec(), bad.header);
+                match bad.cause {
+                    ReadBusinessObjectError::JsonSyntaxError(_) => {},
+                    other => panic!("Unexpected cause {:?}", other)
+                }
+            },
+            other => panic!("Unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn should_report_utf8_error_position() {
+        let mut buf = br#"{"event": "foo"#.to_vec();
+        buf.push(0xff);
+        buf.extend(br#""}"#.iter());
+        buf.push(NUL);
+
+        let mut decoder = BusinessObjectDecoder::new();
+        decoder.feed(&buf).unwrap();
+        match decoder.decode_strict() {
+            Err(ReadBusinessObjectError::BadFrame(bad)) => {
+                assert_eq!(0, bad.offset);
+                match bad.cause {
+                    ReadBusinessObjectError::BufferCharacterDecodingError(e) => {
+                        assert_eq!(14, e.valid_up_to());
+                    },
+                    other => panic!("Unexpected cause {:?}", other)
+                }
+            },
+            other => panic!("Unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn should_resynchronize_after_bad_frames() {
+        let mut buf = BusinessObject::builder().event("foo/bar").payload("ABCDE").build().to_bytes();
+        buf.extend(br#"{"event": "#.iter());
+        buf.push(NUL);
+        let bad_offset = buf.len();
+        buf.extend(br#"[1, 2]"#.iter());
+        buf.push(NUL);
+        buf.extend(BusinessObject::builder().event("bar/foo").build().to_bytes());
+
+        let frames = decode_all(&buf);
+        assert_eq!(4, frames.len());
+
+        assert_eq!("foo/bar", frames[0].as_ref().unwrap().event.as_ref().unwrap());
+        match frames[1] {
+            Err(ReadBusinessObjectError::BadFrame(ref bad)) => { assert_eq!(1, bad.index); },
+            ref other => panic!("Unexpected frame {:?}", other)
+        }
+        match frames[2] {
+            Err(ReadBusinessObjectError::BadFrame(ref bad)) => {
+                assert_eq!(bad_offset as u64, bad.offset);
+                match bad.cause {
+                    ReadBusinessObjectError::JsonSemanticsError(_) => {},
+                    ref other => panic!("Unexpected cause {:?}", other)
+                }
+            },
+            ref other => panic!("Unexpected frame {:?}", other)
+        }
+        assert_eq!("bar/foo", frames[3].as_ref().unwrap().event.as_ref().unwrap());
+    }
+
+    #[test]
+    fn strict_decoding_should_stop_at_bad_frame() {
+        let mut buf = BusinessObject::builder().event("foo/bar").build().to_bytes();
+        let good_len = buf.len();
+        buf.extend(br#"{"event": "#.iter());
+        buf.push(NUL);
+        buf.extend(BusinessObject::builder().event("bar/foo").build().to_bytes());
+
+        let mut decoder = BusinessObjectDecoder::new();
+        decoder.feed(&buf).unwrap();
+        assert_eq!(1, decoder.decode_strict().unwrap().len());
+        assert_eq!(buf.len() - good_len, decoder.buffered());
+        assert!(decoder.decode_strict().is_err());
+        assert!(decoder.decode_strict().is_err());
+    }
+
+    #[test]
+    fn stream_should_keep_reading_after_bad_frames() {
+        let mut buf = br#"{"event": "#.to_vec();
+        buf.push(NUL);
+        buf.extend(BusinessObject::builder().event("foo/bar").build().to_bytes());
+
+        let mut stream = BusinessObjectStream::new(io::Cursor::new(buf));
+        let frames = stream.read_frames().unwrap();
+        assert_eq!(2, frames.len());
+        assert!(frames[0].is_err());
+        assert_eq!("foo/bar", frames[1].as_ref().unwrap().event.as_ref().unwrap());
+
+        stream.socket.get_mut().clear();
+        stream.socket.set_position(0);
+        stream.socket.get_mut().extend(BusinessObject::builder().event("bar/foo").build().to_bytes());
+        let frames = stream.read_frames().unwrap();
+        assert_eq!(1, frames.len());
+        assert_eq!("bar/foo", frames[0].as_ref().unwrap().event.as_ref().unwrap());
+    }
+
+    #[test]
+    fn should_decode_input_fed_one_byte_at_a_time() {
+        let first = BusinessObject::builder()
+            .event("foo/bar")
+            .payload_type("application/octet-stream")
+            .payload(vec![b'A', NUL, b'B', NUL, NUL])
+            .build();
+        let second = BusinessObject::builder().event("bar/foo").build();
+        let third = BusinessObject::builder().event("baz").payload(vec![NUL]).build();
+
+        let mut buf = first.to_bytes();
+        buf.extend(second.to_bytes());
+        buf.extend(third.to_bytes());
+
+        let frames = decode_bytewise(&buf);
+        assert_eq!(3, frames.len());
+        assert!(first.equals_strict(frames[0].as_ref().unwrap()));
+        assert!(second.equals_strict(frames[1].as_ref().unwrap()));
+        assert!(third.equals_strict(frames[2].as_ref().unwrap()));
+    }
+
+    #[test]
+    fn should_resynchronize_when_fed_one_byte_at_a_time() {
+        let mut buf = br#"{"event": "#.to_vec();
+        buf.push(NUL);
+        buf.extend(BusinessObject::builder().event("foo/bar").payload(vec![NUL, NUL]).build().to_bytes());
+
+        let frames = decode_bytewise(&buf);
+        assert_eq!(2, frames.len());
+        assert!(frames[0].is_err());
+        assert_eq!(&[NUL, NUL], frames[1].as_ref().unwrap().payload_bytes());
+    }
+
+    #[test]
+    fn should_keep_partial_frames_buffered() {
+        let obj = BusinessObject::builder().event("foo/bar").payload("ABCDE").build();
+        let bytes = obj.to_bytes();
+        let header_len = bytes.len() - 5;
+
+        let mut decoder = BusinessObjectDecoder::new();
+
+        decoder.feed(&bytes[.. header_len - 3]).unwrap();
+        assert!(decoder.decode().is_empty());
+        assert_eq!(header_len - 3, decoder.buffered());
+
+        decoder.feed(&bytes[header_len - 3 .. header_len + 2]).unwrap();
+        assert!(decoder.decode().is_empty());
+        assert_eq!(2, decoder.buffered());
+
+        decoder.feed(&bytes[header_len + 2 ..]).unwrap();
+        let frames = decoder.decode();
+        assert_eq!(1, frames.len());
+        assert!(obj.equals_strict(frames[0].as_ref().unwrap()));
+        assert_eq!(0, decoder.buffered());
+    }
+
+    #[test]
+    fn should_reject_empty_header() {
+        let mut buf = vec![NUL];
+        buf.extend(BusinessObject::builder().event("foo/bar").build().to_bytes());
+
+        let frames = decode_all(&buf);
+        assert_eq!(2, frames.len());
+        assert!(frames[0].is_err());
+        assert!(frames[1].is_ok());
+    }
+
+    #[test]
+    fn should_reject_header_longer_than_limit() {
+        let obj = BusinessObject::builder().event("foo/bar").build();
+        let header_len = obj.to_bytes().len() - 1;
+        let limits = DecoderLimits { max_header_len: Some(header_len - 1), ..DecoderLimits::default() };
+
+        let mut decoder = BusinessObjectDecoder::with_limits(limits);
+        decoder.feed(&obj.to_bytes()).unwrap();
+        let frames = decoder.decode();
+        assert_eq!(1, frames.len());
+        match frames[0] {
+            Err(ReadBusinessObjectError::BadFrame(ref bad)) => match bad.cause {
+                ReadBusinessObjectError::HeaderTooLong { limit } => assert_eq!(header_len - 1, limit),
+                ref e => panic!("Unexpected cause: {:?}", e)
+            },
+            ref other => panic!("Unexpected result: {:?}", other)
+        }
+
+        assert!(decoder.has_failed());
+        decoder.feed(&obj.to_bytes()).unwrap();
+        assert!(decoder.decode().is_empty());
+        assert_eq!(0, decoder.buffered());
+    }
+
+    #[test]
+    fn decode_strict_should_report_limit_exceeded_after_good_objects() {
+        let obj = BusinessObject::builder().event("foo/bar").build();
+        let limits = DecoderLimits { max_header_len: Some(32), ..DecoderLimits::default() };
+
+        let mut decoder = BusinessObjectDecoder::with_limits(limits);
+        decoder.feed(&obj.to_bytes()).unwrap();
+        decoder.feed(&[b'x'; 40]).unwrap();
+        assert_eq!(vec![obj], decoder.decode_strict().unwrap());
+
+        match decoder.decode_strict() {
+            Err(e) => assert!(e.is_limit_exceeded(), "{:?}", e),
+            other => panic!("Unexpected result: {:?}", other)
+        }
+        assert!(decoder.decode_strict().unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_reject_unterminated_header_longer_than_limit() {
+        let limits = DecoderLimits { max_header_len: Some(16), ..DecoderLimits::default() };
+        let mut decoder = BusinessObjectDecoder::with_limits(limits);
+
+        decoder.feed(b"{\"event\": ").unwrap();
+        assert!(decoder.decode().is_empty());
+        decoder.feed(b"\"foo/bar\"").unwrap();
+
+        let frames = decoder.decode();
+        assert_eq!(1, frames.len());
+        assert!(frames[0].as_ref().unwrap_err().is_limit_exceeded());
+    }
+
+    #[test]
+    fn should_reject_payload_larger_than_limit() {
+        let small = BusinessObject::builder().event("foo/bar").payload("tiny").build();
+        let large = BusinessObject::builder().event("foo/bar").payload(vec![0; 100]).build();
+        let limits = DecoderLimits { max_payload_size: Some(99), ..DecoderLimits::default() };
+
+        let mut decoder = BusinessObjectDecoder::with_limits(limits);
+        decoder.feed(&small.to_bytes()).unwrap();
+        decoder.feed(&large.to_bytes()[.. 40]).unwrap();
+        let frames = decoder.decode();
+        assert_eq!(2, frames.len());
+        assert_eq!(small, *frames[0].as_ref().unwrap());
+        match frames[1] {
+            Err(ReadBusinessObjectError::BadFrame(ref bad)) => match bad.cause {
+                ReadBusinessObjectError::PayloadTooLarge { size, limit } => {
+                    assert_eq!(100, size);
+                    assert_eq!(99, limit);
+                },
+                ref e => panic!("Unexpected cause: {:?}", e)
+            },
+            ref other => panic!("Unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn should_reject_input_beyond_buffer_limit() {
+        let obj = BusinessObject::builder().event("foo/bar").payload(vec![0; 100]).build();
+        let bytes = obj.to_bytes();
+        let limits = DecoderLimits { max_buffered: Some(bytes.len()), ..DecoderLimits::default() };
+
+        let mut decoder = BusinessObjectDecoder::with_limits(limits);
+        decoder.feed(&bytes).unwrap();
+        assert_eq!(1, decoder.decode().len());
+
+        decoder.feed(&bytes[.. 50]).unwrap();
+        match decoder.feed(&bytes) {
+            Err(ReadBusinessObjectError::BufferLimitExceeded { buffered, limit }) => {
+                assert_eq!(50 + bytes.len(), buffered);
+                assert_eq!(bytes.len(), limit);
+            },
+            other => panic!("Unexpected result: {:?}", other)
+        }
+        assert!(decoder.has_failed());
+        assert!(decoder.decode().is_empty());
+    }
+
+    #[test]
+    fn stream_should_fail_when_exceeding_buffer_limit() {
+        let obj = BusinessObject::builder().event("foo/bar").payload(vec![0; 100]).build();
+        let limits = DecoderLimits { max_buffered: Some(64), ..DecoderLimits::default() };
+
+        let mut stream = BusinessObjectStream::with_limits(io::Cursor::new(obj.to_bytes()), limits);
+        // Reads stop at the limit, so it takes a few to run into it.
+        for _ in 0 .. 10 {
+            match stream.read_frames() {
+                Err(ref e) => {
+                    assert!(e.is_limit_exceeded());
+                    return;
+                },
+                Ok(frames) => assert!(frames.is_empty())
+            }
+        }
+        panic!("Buffer limit not enforced");
+    }
+
+    #[test]
+    fn stream_should_read_bursts_of_small_frames_within_buffer_limit() {
+        let objects: Vec<BusinessObject> = (0 .. 20)
+            .map(|i| BusinessObject::builder().event(format!("foo/{}", i)).build())
+            .collect();
+        let bytes: Vec<u8> = objects.iter().flat_map(|obj| obj.to_bytes()).collect();
+        let limits = DecoderLimits { max_buffered: Some(64), ..DecoderLimits::default() };
+
+        let mut stream = BusinessObjectStream::with_limits(io::Cursor::new(bytes), limits);
+        let mut read = Vec::new();
+        while read.len() < objects.len() {
+            read.extend(stream.read_business_objects().unwrap());
+        }
+        assert_eq!(objects, read);
+    }
+
+    #[test]
+    fn stream_should_read_frames_split_across_reads() {
+        /// Hands out at most `chunk` bytes per read.
+        struct TrickleReader {
+            data: io::Cursor<Vec<u8>>,
+            chunk: usize
+        }
+
+        impl io::Read for TrickleReader {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = ::std::cmp::min(self.chunk, buf.len());
+                self.data.read(&mut buf[.. n])
+            }
+        }
+
+        impl Write for TrickleReader {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let obj = BusinessObject::builder().event("foo/bar").payload(vec![NUL; 10]).build();
+        let mut buf = obj.to_bytes();
+        buf.extend(obj.to_bytes());
+
+        let mut stream = BusinessObjectStream::new(TrickleReader { data: io::Cursor::new(buf), chunk: 4 });
+        let mut objects = Vec::new();
+        while objects.len() < 2 {
+            objects.extend(stream.read_business_objects().unwrap());
+        }
+
+        assert!(obj.equals_strict(&objects[0]));
+        assert!(obj.equals_strict(&objects[1]));
     }
 }
@@ -10,6 +10,8 @@ mod object;
 
 pub mod subscription;
 pub mod io;
-pub use object::{BusinessObject, Payload};
+pub use object::{BadFrame, BusinessObject, BusinessObjectBuilder, DecodedPayload, DecodePayloadError, Payload,
+                 ReadBusinessObjectError, SerializeBusinessObjectError, HEADER_EXCERPT_LEN,
+                 json_equals_canonical, parse_type};
 
 
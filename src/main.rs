@@ -1,4 +1,3 @@
-use std::collections::BTreeMap;
 use std::io::Write;
 use std::net::TcpStream;
 
@@ -15,21 +14,14 @@ fn main() {
     // socket_stream.set_nodelay(true);
     let mut stream = BusinessObjectStream::new(socket_stream);
 
-    let mut metadata = BTreeMap::new();
-    // metadata.insert("subscriptions".to_string(),
-    //                 vec!["@routing/*".to_string(), "@services/*".to_string(),
-    //                      "@ping".to_string(), "@pong".to_string()].to_json());
-    metadata.insert("subscriptions".to_string(), vec!["*".to_string()].to_json());
-
-    let subscription = BusinessObject {
-        _type: None,
-        payload: None,
-        size: None,
-        event: Some("routing/subscribe".to_string()),
-        metadata: metadata,
-    };
+    let subscription = BusinessObject::builder()
+        .event("routing/subscribe")
+        // .metadata("subscriptions", &vec!["@routing/*".to_string(), "@services/*".to_string(),
+        //                                  "@ping".to_string(), "@pong".to_string()])
+        .metadata("subscriptions", &vec!["*".to_string()])
+        .build();
 
-    match stream.write(&subscription.to_bytes()) {
+    match stream.write_business_object(&subscription) {
         Ok(bytes) => {
             println!("Send ok: {} bytes", bytes);
         },
@@ -43,15 +35,9 @@ fn main() {
     let obj = stream.read_business_objects().unwrap();
     println!("Got: {:?}", &obj.to_json());
 
-    let ping = BusinessObject {
-        _type: None,
-        payload: None,
-        size: None,
-        event: Some("ping".to_string()),
-        metadata: BTreeMap::new(),
-    };
+    let ping = BusinessObject::builder().event("ping").build();
 
-    println!("Wrote {} bytes.", stream.write(&ping.to_bytes()).unwrap());
+    println!("Wrote {} bytes.", stream.write_business_object(&ping).unwrap());
 
     let obj = stream.read_business_objects().unwrap();
     println!("Got: {:?}", &obj.to_json());
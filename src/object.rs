@@ -3,8 +3,13 @@ use std::collections::BTreeMap;
 use std::error;
 use std::fmt;
 use std::io;
+use std::io::Write;
+use std::str;
+use std::sync::Arc;
 
-use rustc_serialize::json::{ToJson, Json};
+use rustc_serialize::json::{self, ToJson, Json};
+
+use io::WriteBusinessObject;
 
 
 #[derive(Debug, Clone)]
@@ -17,22 +22,189 @@ pub struct BusinessObject {
 }
 
 
-#[derive(Eq, PartialEq, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub enum Payload {
-    Bytes(Vec<u8>)
+    Bytes(Vec<u8>),
+    /// Immutable bytes shared between clones; cloning never copies them.
+    Shared(Arc<[u8]>)
 }
 
 
+/// How much of an undecodable header `BadFrame` keeps for diagnostics.
+pub const HEADER_EXCERPT_LEN: usize = 256;
+
+
 #[derive(Debug)]
 pub enum ReadBusinessObjectError {
     ReadError(io::Error),
 
-    JsonSemanticsError(&'static str),
-    JsonSyntaxError(String, String),
-    BufferCharacterDecodingError
+    /// The header is valid JSON but not a business object; says what it is
+    /// instead.
+    JsonSemanticsError(String),
+    JsonSyntaxError(json::ParserError),
+    BufferCharacterDecodingError(str::Utf8Error),
+
+    /// A header ran past the configured maximum length without its NUL.
+    HeaderTooLong { limit: usize },
+    /// A header declared a payload bigger than the configured maximum.
+    PayloadTooLarge { size: usize, limit: usize },
+    /// Reading more would buffer more than the configured maximum.
+    BufferLimitExceeded { buffered: usize, limit: usize },
+
+    /// A frame in a stream couldn't be decoded; the cause is one of the
+    /// variants above.
+    BadFrame(Box<BadFrame>)
+}
+
+
+/// Where an undecodable frame was found in a stream, what its header looked
+/// like and why it couldn't be decoded.
+#[derive(Debug)]
+pub struct BadFrame {
+    /// Byte offset of the start of the frame from the start of the stream.
+    pub offset: u64,
+    /// Index of the frame in the stream, counting from zero.
+    pub index: u64,
+    /// The first `HEADER_EXCERPT_LEN` bytes of the header.
+    pub header: Vec<u8>,
+    /// Length of the whole header, without its NUL terminator.
+    pub header_len: usize,
+    pub cause: ReadBusinessObjectError
+}
+
+
+impl ReadBusinessObjectError {
+    /// Whether the error is about a peer exceeding a configured limit.
+    /// Such streams can't be decoded any further and should be closed.
+    pub fn is_limit_exceeded(&self) -> bool {
+        match *self {
+            ReadBusinessObjectError::HeaderTooLong { .. } |
+            ReadBusinessObjectError::PayloadTooLarge { .. } |
+            ReadBusinessObjectError::BufferLimitExceeded { .. } => true,
+            ReadBusinessObjectError::BadFrame(ref bad) => bad.cause.is_limit_exceeded(),
+            _ => false
+        }
+    }
+
+    /// Wraps a decoding error with the position and header of the frame
+    /// it occurred in.
+    pub fn in_frame(self, offset: u64, index: u64, header: &[u8]) -> ReadBusinessObjectError {
+        let excerpt_len = ::std::cmp::min(header.len(), HEADER_EXCERPT_LEN);
+
+        ReadBusinessObjectError::BadFrame(Box::new(BadFrame {
+            offset,
+            index,
+            header: header[.. excerpt_len].to_vec(),
+            header_len: header.len(),
+            cause: self
+        }))
+    }
+}
+
+
+/// Reasons a `BusinessObject` cannot be put on the wire.
+#[derive(Debug)]
+pub enum SerializeBusinessObjectError {
+    /// `size` and the length of the payload disagree.
+    SizeMismatch { size: usize, payload_len: usize },
+    /// There is a non-empty payload but no `size`.
+    PayloadWithoutSize(usize),
+    /// `size` is set but there is no payload.
+    SizeWithoutPayload(usize),
+    WriteError(io::Error)
+}
+
+
+impl Payload {
+    pub fn as_bytes(&self) -> &[u8] {
+        match *self {
+            Payload::Bytes(ref bytes) => bytes,
+            Payload::Shared(ref bytes) => bytes
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.as_bytes().is_empty()
+    }
+
+    /// Converts the payload into its shared form, copying it at most once.
+    pub fn into_shared(self) -> Payload {
+        match self {
+            Payload::Bytes(bytes) => Payload::Shared(Arc::from(bytes)),
+            shared => shared
+        }
+    }
+}
+
+
+impl PartialEq for Payload {
+    fn eq(&self, other: &Payload) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
 }
 
+impl Eq for Payload {}
+
+
+impl From<Vec<u8>> for Payload {
+    fn from(bytes: Vec<u8>) -> Payload {
+        Payload::Bytes(bytes)
+    }
+}
+
+impl<'a> From<&'a [u8]> for Payload {
+    fn from(bytes: &'a [u8]) -> Payload {
+        Payload::Bytes(bytes.to_vec())
+    }
+}
 
+impl From<String> for Payload {
+    fn from(text: String) -> Payload {
+        Payload::Bytes(text.into_bytes())
+    }
+}
+
+impl<'a> From<&'a str> for Payload {
+    fn from(text: &'a str) -> Payload {
+        Payload::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+impl From<Arc<[u8]>> for Payload {
+    fn from(bytes: Arc<[u8]>) -> Payload {
+        Payload::Shared(bytes)
+    }
+}
+
+
+/// Reasons the payload of a `BusinessObject` can't be decoded as its `type`
+/// says.
+#[derive(Debug)]
+pub enum DecodePayloadError {
+    /// The `type` doesn't allow the requested decoding.
+    TypeMismatch { expected: &'static str, found: Option<String> },
+    UnsupportedCharset(String),
+    /// The payload isn't valid in its charset, from `position` onwards.
+    InvalidCharacters { charset: String, position: usize },
+    JsonSyntaxError(json::ParserError)
+}
+
+
+/// A payload decoded according to the `type` of its object.
+#[derive(Debug, PartialEq)]
+pub enum DecodedPayload<'a> {
+    Text(String),
+    Json(Json),
+    Bytes(&'a [u8])
+}
+
+
+/// Compares objects on event, type, size and payload only; use
+/// `BusinessObject::equals_strict` to compare metadata as well.
 impl PartialEq for BusinessObject {
     fn eq(&self, other: &BusinessObject) -> bool {
         self.event == other.event &&
@@ -43,25 +215,210 @@ impl PartialEq for BusinessObject {
 }
 
 
-fn extract_reason(error: &ReadBusinessObjectError) -> &str {
-    match *error {
-        ReadBusinessObjectError::JsonSemanticsError(ref reason) => reason,
-        ReadBusinessObjectError::JsonSyntaxError(_, ref reason) => reason,
-        ReadBusinessObjectError::BufferCharacterDecodingError => "Character encoding error",
-        ReadBusinessObjectError::ReadError(_) => "Read error"
+/// Compares JSON numbers by value, so that `1`, `1u64` and `1.0` are equal
+/// no matter which variant the parser or `ToJson` picked for them.
+fn json_number_equals(a: &Json, b: &Json) -> bool {
+    fn as_integer(value: &Json) -> Option<i128> {
+        match *value {
+            Json::I64(i) => Some(i128::from(i)),
+            Json::U64(u) => Some(i128::from(u)),
+            Json::F64(f) if f.fract() == 0.0 && f.abs() < 1e38 => Some(f as i128),
+            _ => None
+        }
+    }
+
+    match (as_integer(a), as_integer(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a.as_f64() == b.as_f64()
+    }
+}
+
+
+/// Structural JSON equality with canonical number handling.
+pub fn json_equals_canonical(a: &Json, b: &Json) -> bool {
+    match (a, b) {
+        (Json::Array(a), Json::Array(b)) =>
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| json_equals_canonical(a, b)),
+        (Json::Object(a), Json::Object(b)) =>
+            a.len() == b.len() &&
+            a.iter().zip(b.iter()).all(|((ka, va), (kb, vb))| ka == kb && json_equals_canonical(va, vb)),
+        (a, b) if a.is_number() && b.is_number() => json_number_equals(a, b),
+        (a, b) => a == b
     }
 }
 
 
 impl fmt::Display for ReadBusinessObjectError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "{:?}", extract_reason(self))
+        match *self {
+            ReadBusinessObjectError::ReadError(ref e) =>
+                write!(f, "Read error: {}", e),
+            ReadBusinessObjectError::JsonSemanticsError(ref reason) =>
+                write!(f, "{}", reason),
+            ReadBusinessObjectError::JsonSyntaxError(ref e) =>
+                write!(f, "Invalid JSON in header: {}", e),
+            ReadBusinessObjectError::BufferCharacterDecodingError(ref e) =>
+                write!(f, "Header is not valid UTF-8 after byte {}", e.valid_up_to()),
+            ReadBusinessObjectError::HeaderTooLong { limit } =>
+                write!(f, "Header is longer than {} bytes", limit),
+            ReadBusinessObjectError::PayloadTooLarge { size, limit } =>
+                write!(f, "Payload of {} bytes is larger than {} bytes", size, limit),
+            ReadBusinessObjectError::BufferLimitExceeded { buffered, limit } =>
+                write!(f, "Buffering {} bytes would exceed {} bytes", buffered, limit),
+            ReadBusinessObjectError::BadFrame(ref bad) =>
+                write!(f, "{}", bad)
+        }
     }
 }
 
 impl error::Error for ReadBusinessObjectError {
-    fn description(&self) -> &str {
-        extract_reason(self)
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ReadBusinessObjectError::ReadError(ref e) => Some(e),
+            ReadBusinessObjectError::JsonSemanticsError(_) |
+            ReadBusinessObjectError::HeaderTooLong { .. } |
+            ReadBusinessObjectError::PayloadTooLarge { .. } |
+            ReadBusinessObjectError::BufferLimitExceeded { .. } => None,
+            ReadBusinessObjectError::JsonSyntaxError(ref e) => Some(e),
+            ReadBusinessObjectError::BufferCharacterDecodingError(ref e) => Some(e),
+            ReadBusinessObjectError::BadFrame(ref bad) => Some(&bad.cause)
+        }
+    }
+}
+
+
+impl fmt::Display for BadFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Bad frame #{} at byte {}: {}; header ", self.index, self.offset, self.cause)?;
+
+        if self.header_len > self.header.len() {
+            write!(f, "({} of {} bytes) ", self.header.len(), self.header_len)?;
+        }
+
+        write!(f, "{:?}", String::from_utf8_lossy(&self.header))
+    }
+}
+
+
+impl fmt::Display for SerializeBusinessObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SerializeBusinessObjectError::SizeMismatch { size, payload_len } =>
+                write!(f, "Size {} doesn't match payload length {}", size, payload_len),
+            SerializeBusinessObjectError::PayloadWithoutSize(payload_len) =>
+                write!(f, "Payload of {} bytes without size", payload_len),
+            SerializeBusinessObjectError::SizeWithoutPayload(size) =>
+                write!(f, "Size {} without payload", size),
+            SerializeBusinessObjectError::WriteError(ref e) =>
+                write!(f, "Write error: {}", e)
+        }
+    }
+}
+
+impl error::Error for SerializeBusinessObjectError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            SerializeBusinessObjectError::WriteError(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for SerializeBusinessObjectError {
+    fn from(e: io::Error) -> SerializeBusinessObjectError {
+        SerializeBusinessObjectError::WriteError(e)
+    }
+}
+
+
+impl fmt::Display for DecodePayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            DecodePayloadError::TypeMismatch { expected, found: Some(ref found) } =>
+                write!(f, "Expected {} payload, type is {}", expected, found),
+            DecodePayloadError::TypeMismatch { expected, found: None } =>
+                write!(f, "Expected {} payload, object has no type", expected),
+            DecodePayloadError::UnsupportedCharset(ref charset) =>
+                write!(f, "Unsupported charset {}", charset),
+            DecodePayloadError::InvalidCharacters { ref charset, position } =>
+                write!(f, "Payload is not valid {} at byte {}", charset, position),
+            DecodePayloadError::JsonSyntaxError(ref e) =>
+                write!(f, "Payload is not valid JSON: {}", e)
+        }
+    }
+}
+
+impl error::Error for DecodePayloadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            DecodePayloadError::JsonSyntaxError(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+
+/// Splits a `type` such as `text/plain; charset=utf-8` into the media type
+/// and the value of its `charset` parameter, if there is one.
+pub fn parse_type(_type: &str) -> (&str, Option<&str>) {
+    let mut parts = _type.split(';');
+    let media_type = parts.next().unwrap_or("").trim();
+
+    let charset = parts
+        .filter_map(|parameter| {
+            let mut pair = parameter.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("charset") =>
+                    Some(value.trim().trim_matches('"')),
+                _ => None
+            }
+        })
+        .next();
+
+    (media_type, charset)
+}
+
+
+fn is_json_type(media_type: &str) -> bool {
+    media_type.eq_ignore_ascii_case("application/json") || media_type.to_ascii_lowercase().ends_with("+json")
+}
+
+
+fn is_text_type(media_type: &str) -> bool {
+    media_type.to_ascii_lowercase().starts_with("text/") || is_json_type(media_type)
+}
+
+
+/// Decodes `bytes` in `charset`, which defaults to UTF-8.
+fn decode_text(bytes: &[u8], charset: Option<&str>) -> Result<String, DecodePayloadError> {
+    let charset = charset.unwrap_or("utf-8");
+
+    match &charset.to_ascii_lowercase()[..] {
+        "utf-8" | "utf8" => match ::std::str::from_utf8(bytes) {
+            Ok(text) => Ok(text.to_string()),
+            Err(e) => Err(DecodePayloadError::InvalidCharacters { charset: charset.to_string(),
+                                                                  position: e.valid_up_to() })
+        },
+        "us-ascii" | "ascii" => match bytes.iter().position(|byte| !byte.is_ascii()) {
+            None => Ok(bytes.iter().map(|&byte| byte as char).collect()),
+            Some(position) => Err(DecodePayloadError::InvalidCharacters { charset: charset.to_string(),
+                                                                          position })
+        },
+        "iso-8859-1" | "latin1" | "latin-1" => Ok(bytes.iter().map(|&byte| byte as char).collect()),
+        _ => Err(DecodePayloadError::UnsupportedCharset(charset.to_string()))
+    }
+}
+
+
+/// What kind of JSON value `json` is, with its article, for error messages.
+fn json_kind(json: &Json) -> &'static str {
+    match *json {
+        Json::Object(_) => "an object",
+        Json::Array(_) => "an array",
+        Json::String(_) => "a string",
+        Json::I64(_) | Json::U64(_) | Json::F64(_) => "a number",
+        Json::Boolean(_) => "a boolean",
+        Json::Null => "null"
     }
 }
 
@@ -74,9 +431,9 @@ impl ToJson for BusinessObject {
             d.insert(key.to_string(), value.clone());
         }
 
-        if self._type.is_some() { d.insert("type".to_string(), (&self._type).clone().unwrap().to_json()); }
-        if self.size.is_some() { d.insert("size".to_string(), (&self.size).clone().unwrap().to_json()); }
-        if self.event.is_some() { d.insert("event".to_string(), (&self.event).clone().unwrap().to_json()); }
+        if let Some(ref _type) = self._type { d.insert("type".to_string(), _type.to_json()); }
+        if let Some(size) = self.size { d.insert("size".to_string(), size.to_json()); }
+        if let Some(ref event) = self.event { d.insert("event".to_string(), event.to_json()); }
 
         Json::Object(d)
     }
@@ -87,25 +444,117 @@ impl BusinessObject {
     pub fn from_json(obj: &Json) -> Result<BusinessObject, ReadBusinessObjectError> {
         match obj.as_object() {
             Some(btree_obj) => Ok(btree_obj.to_business_object()),
-            None => Err(ReadBusinessObjectError::JsonSemanticsError("Unsupported JSON type"))
+            None => Err(ReadBusinessObjectError::JsonSemanticsError(
+                format!("Header is {}, not a JSON object", json_kind(obj))))
         }
     }
 
+    pub fn builder() -> BusinessObjectBuilder {
+        BusinessObjectBuilder::new()
+    }
+
+    /// Serializes the object into a wire frame.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` and `payload` disagree; use `try_to_bytes` for
+    /// objects that weren't built with `BusinessObjectBuilder`.
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut result = self.to_json().to_string().into_bytes();
-        result.push(b'\0');
+        match self.try_to_bytes() {
+            Ok(bytes) => bytes,
+            Err(e) => panic!("Cannot serialize {:?}: {}", self, e)
+        }
+    }
+
+    pub fn try_to_bytes(&self) -> Result<Vec<u8>, SerializeBusinessObjectError> {
+        let mut result = Vec::new();
+        self.write_to(&mut result)?;
+        Ok(result)
+    }
+
+    /// Writes the object as a wire frame into `writer`, returning the
+    /// number of bytes written. Nothing is written if `size` and `payload`
+    /// disagree.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<usize, SerializeBusinessObjectError> {
+        writer.write_business_object(self)
+    }
 
+    /// Returns the payload bytes after checking them against `size`.
+    pub(crate) fn checked_payload(&self) -> Result<&[u8], SerializeBusinessObjectError> {
+        let payload: &[u8] = match self.payload {
+            Some(ref payload) => payload.as_bytes(),
+            None => &[]
+        };
+
+        match self.size {
+            Some(size) if size == payload.len() => Ok(payload),
+            Some(size) if payload.is_empty() && self.payload.is_none() =>
+                Err(SerializeBusinessObjectError::SizeWithoutPayload(size)),
+            Some(size) =>
+                Err(SerializeBusinessObjectError::SizeMismatch { size, payload_len: payload.len() }),
+            None if payload.is_empty() => Ok(payload),
+            None => Err(SerializeBusinessObjectError::PayloadWithoutSize(payload.len()))
+        }
+    }
+
+    /// The payload bytes, or an empty slice if there is no payload.
+    pub fn payload_bytes(&self) -> &[u8] {
         match self.payload {
-            Some(Payload::Bytes(ref payload)) => {
-                assert!(self.has_payload());
-                assert!(self.size.unwrap() == payload.len());
+            Some(ref payload) => payload.as_bytes(),
+            None => &[]
+        }
+    }
+
+    /// Decodes a `text/*` or JSON payload into a string, honouring the
+    /// `charset` parameter of `type`.
+    pub fn payload_text(&self) -> Result<String, DecodePayloadError> {
+        let (media_type, charset) = self.parsed_type();
+
+        match media_type {
+            Some(media_type) if is_text_type(media_type) => decode_text(self.payload_bytes(), charset),
+            _ => Err(DecodePayloadError::TypeMismatch { expected: "text", found: self._type.clone() })
+        }
+    }
+
+    /// Parses an `application/json` or `+json` payload.
+    pub fn payload_json(&self) -> Result<Json, DecodePayloadError> {
+        let (media_type, charset) = self.parsed_type();
 
-                result.extend(payload);
+        match media_type {
+            Some(media_type) if is_json_type(media_type) => {
+                let text = decode_text(self.payload_bytes(), charset)?;
+                Json::from_str(&text).map_err(DecodePayloadError::JsonSyntaxError)
             },
-            None => {}
+            _ => Err(DecodePayloadError::TypeMismatch { expected: "JSON", found: self._type.clone() })
         }
+    }
 
-        result
+    /// Decodes the payload as JSON, text or raw bytes, depending on `type`.
+    pub fn decoded_payload(&self) -> Result<DecodedPayload<'_>, DecodePayloadError> {
+        match self.parsed_type().0 {
+            Some(media_type) if is_json_type(media_type) => self.payload_json().map(DecodedPayload::Json),
+            Some(media_type) if is_text_type(media_type) => self.payload_text().map(DecodedPayload::Text),
+            _ => Ok(DecodedPayload::Bytes(self.payload_bytes()))
+        }
+    }
+
+    fn parsed_type(&self) -> (Option<&str>, Option<&str>) {
+        match self._type {
+            Some(ref _type) => {
+                let (media_type, charset) = parse_type(_type);
+                (Some(media_type), charset)
+            },
+            None => (None, None)
+        }
+    }
+
+    /// Like `==`, but also compares metadata. Numbers in metadata are
+    /// compared by value (see `json_equals_canonical`).
+    pub fn equals_strict(&self, other: &BusinessObject) -> bool {
+        self == other &&
+            self.metadata.len() == other.metadata.len() &&
+            self.metadata.iter().zip(other.metadata.iter())
+                .all(|((ka, va), (kb, vb))| ka == kb && json_equals_canonical(va, vb))
     }
 
     pub fn has_payload(&self) -> bool {
@@ -118,29 +567,89 @@ impl BusinessObject {
     pub fn natures(&self) -> Vec<&str> {
         let mut result = Vec::new();
 
-        match self.metadata.get("natures") {
-            Some(natures) => {
-                match natures.as_array() {
-                    Some(natures) => {
-                        for item in natures {
-                            match item.as_string() {
-                                Some(nature) => { result.push(nature); },
-                                _ => { trace!("Cannot use {} as a nature", item); }
-                            }
-                        }
-                    },
-                    None => {}
+        if let Some(natures) = self.metadata.get("natures").and_then(|natures| natures.as_array()) {
+            for item in natures {
+                match item.as_string() {
+                    Some(nature) => { result.push(nature); },
+                    _ => { trace!("Cannot use {} as a nature", item); }
                 }
-
             }
-            None => {}
-        };
+        }
 
         result
     }
 }
 
 
+/// Fluent constructor for `BusinessObject`.
+///
+/// `size` is always derived from the payload, so a built object always
+/// passes the size checks in `try_to_bytes`.
+#[derive(Debug, Clone, Default)]
+pub struct BusinessObjectBuilder {
+    event: Option<String>,
+    _type: Option<String>,
+    payload: Option<Payload>,
+    metadata: BTreeMap<String,Json>
+}
+
+
+impl BusinessObjectBuilder {
+    pub fn new() -> BusinessObjectBuilder {
+        BusinessObjectBuilder::default()
+    }
+
+    pub fn event<S: Into<String>>(mut self, event: S) -> BusinessObjectBuilder {
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn payload_type<S: Into<String>>(mut self, payload_type: S) -> BusinessObjectBuilder {
+        self._type = Some(payload_type.into());
+        self
+    }
+
+    /// Sets the `natures` metadata key, replacing any natures given earlier.
+    pub fn natures<I, S>(mut self, natures: I) -> BusinessObjectBuilder
+        where I: IntoIterator<Item = S>, S: Into<String>
+    {
+        let natures: Vec<String> = natures.into_iter().map(|nature| nature.into()).collect();
+        self.metadata.insert("natures".to_string(), natures.to_json());
+        self
+    }
+
+    /// Sets an arbitrary metadata key. `event`, `type` and `size` have
+    /// dedicated setters and are ignored here.
+    pub fn metadata<K: Into<String>, V: ToJson + ?Sized>(mut self, key: K, value: &V) -> BusinessObjectBuilder {
+        let key = key.into();
+        if key == "event" || key == "type" || key == "size" {
+            warn!("Ignoring reserved metadata key {:?}", key);
+        } else {
+            self.metadata.insert(key, value.to_json());
+        }
+        self
+    }
+
+    pub fn payload<P: Into<Payload>>(mut self, payload: P) -> BusinessObjectBuilder {
+        self.payload = Some(payload.into());
+        self
+    }
+
+    pub fn build(self) -> BusinessObject {
+        // An empty payload is equivalent to no payload on the wire.
+        let payload = self.payload.and_then(|payload| if payload.is_empty() { None } else { Some(payload) });
+
+        BusinessObject {
+            event: self.event,
+            _type: self._type,
+            size: payload.as_ref().map(|payload| payload.len()),
+            payload,
+            metadata: self.metadata
+        }
+    }
+}
+
+
 trait ToBusinessObject {
     fn to_business_object(&self) -> BusinessObject;
 }
@@ -156,30 +665,17 @@ impl ToBusinessObject for BTreeMap<String,Json> {
             metadata: BTreeMap::new()
         };
 
-        let event = self.get("event");
-        if event.is_some() {
-            let value = event.unwrap().as_string();
-            if value.is_some() {
-                result.event = Some(value.unwrap().to_string());
-            }
+        if let Some(value) = self.get("event").and_then(|event| event.as_string()) {
+            result.event = Some(value.to_string());
         }
 
-        let _type = self.get("type");
-        if _type.is_some() {
-            let value = _type.unwrap().as_string();
-            if value.is_some() {
-                result._type = Some(value.unwrap().to_string());
-            }
+        if let Some(value) = self.get("type").and_then(|_type| _type.as_string()) {
+            result._type = Some(value.to_string());
         }
 
-        let size = self.get("size");
-        if size.is_some() {
-            let value = size.unwrap().as_u64();
-            if value.is_some() {
-                let s = value.unwrap();
-                if s > 0 {
-                    result.size = Some(s as usize);
-                }
+        if let Some(s) = self.get("size").and_then(|size| size.as_u64()) {
+            if s > 0 {
+                result.size = Some(s as usize);
             }
         }
 
@@ -198,27 +694,22 @@ impl ToBusinessObject for BTreeMap<String,Json> {
 
 #[cfg(test)]
 mod tests {
-    use std::collections::BTreeMap;
     use rustc_serialize::json::{Json, ToJson};
 
-    use super::BusinessObject;
+    use std::error::Error;
+
+    use super::{BusinessObject, DecodedPayload, DecodePayloadError, Payload, ReadBusinessObjectError,
+                SerializeBusinessObjectError, HEADER_EXCERPT_LEN, json_equals_canonical, parse_type};
 
 
     #[test]
     fn smoke_test_serialization_and_deserialization() {
-        let mut metadata = BTreeMap::new();
-        metadata.insert("subscriptions".to_string(),
-                        vec!["@routing/*".to_string(), "@services/*".to_string(),
-                             "@ping".to_string(), "@pong".to_string()].to_json());
-        metadata.insert("subscriptions".to_string(), vec!["*".to_string()].to_json());
-
-        let subscription = BusinessObject {
-            _type: None,
-            payload: None,
-            size: None,
-            event: Some("routing/subscribe".to_string()),
-            metadata: metadata,
-        };
+        let subscription = BusinessObject::builder()
+            .event("routing/subscribe")
+            .metadata("subscriptions", &["@routing/*", "@services/*", "@ping", "@pong"].iter()
+                      .map(|s| s.to_string()).collect::<Vec<String>>())
+            .metadata("subscriptions", &vec!["*".to_string()])
+            .build();
 
         let json_repr_from = subscription.to_json();
         let string_repr = json_repr_from.to_string();
@@ -226,6 +717,255 @@ mod tests {
         let back = BusinessObject::from_json(&json_repr_to).unwrap();
 
         assert!(json_repr_from == json_repr_to);
-        assert!(subscription == back);
+        assert!(subscription.equals_strict(&back));
+    }
+
+    #[test]
+    fn equals_strict_should_compare_metadata() {
+        let obj = BusinessObject::builder()
+            .event("routing/subscribe")
+            .natures(vec!["hasselhoff"])
+            .metadata("subscriptions", &vec!["*".to_string()])
+            .build();
+
+        let mut lost_natures = obj.clone();
+        lost_natures.metadata.remove("natures");
+        assert!(obj == lost_natures);
+        assert!(!obj.equals_strict(&lost_natures));
+
+        let mut changed_subscriptions = obj.clone();
+        changed_subscriptions.metadata.insert("subscriptions".to_string(), vec!["@foo".to_string()].to_json());
+        assert!(!obj.equals_strict(&changed_subscriptions));
+    }
+
+    #[test]
+    fn json_equals_canonical_should_compare_numbers_by_value() {
+        assert!(json_equals_canonical(&Json::I64(1), &Json::U64(1)));
+        assert!(json_equals_canonical(&Json::U64(1), &Json::F64(1.0)));
+        assert!(!json_equals_canonical(&Json::I64(-1), &Json::U64(1)));
+        assert!(!json_equals_canonical(&Json::F64(1.5), &Json::I64(1)));
+        assert!(json_equals_canonical(&vec![1i64, 2].to_json(), &Json::from_str("[1, 2.0]").unwrap()));
+        assert!(!json_equals_canonical(&Json::String("1".to_string()), &Json::I64(1)));
+    }
+
+    #[test]
+    fn round_trip_should_preserve_metadata_numbers() {
+        let obj = BusinessObject::builder()
+            .event("sensors/temperature")
+            .metadata("reading", &-3)
+            .metadata("sequence", &vec![1u64, 2, 3])
+            .payload("ABCDE")
+            .build();
+
+        let bytes = obj.to_bytes();
+        let header = ::std::str::from_utf8(&bytes[.. bytes.len() - 6]).unwrap();
+        let mut back = BusinessObject::from_json(&Json::from_str(header).unwrap()).unwrap();
+        back.payload = Some(Payload::from("ABCDE"));
+
+        assert!(obj.equals_strict(&back));
+    }
+
+    #[test]
+    fn builder_should_derive_size_from_payload() {
+        let obj = BusinessObject::builder()
+            .event("foo/bar")
+            .payload_type("text/plain")
+            .payload("ABCDE")
+            .build();
+
+        assert_eq!(Some(5), obj.size);
+        assert_eq!(Some(Payload::Bytes(b"ABCDE".to_vec())), obj.payload);
+        assert_eq!(b"ABCDE", &obj.to_bytes()[obj.to_bytes().len() - 5 ..]);
+    }
+
+    #[test]
+    fn builder_should_treat_empty_payload_as_no_payload() {
+        let obj = BusinessObject::builder().event("foo/bar").payload(Vec::<u8>::new()).build();
+
+        assert_eq!(None, obj.size);
+        assert_eq!(None, obj.payload);
+        assert!(!obj.has_payload());
+    }
+
+    #[test]
+    fn builder_should_set_natures_and_metadata() {
+        let obj = BusinessObject::builder()
+            .natures(vec!["hasselhoff", "tweet"])
+            .metadata("id", "abc")
+            .metadata("size", &1000)
+            .build();
+
+        assert_eq!(vec!["hasselhoff", "tweet"], obj.natures());
+        assert_eq!(Some(&"abc".to_json()), obj.metadata.get("id"));
+        assert_eq!(None, obj.metadata.get("size"));
+        assert_eq!(None, obj.size);
+    }
+
+    #[test]
+    fn try_to_bytes_should_reject_size_mismatch() {
+        let mut obj = BusinessObject::builder().event("foo/bar").payload("ABCDE").build();
+        obj.size = Some(4);
+
+        match obj.try_to_bytes() {
+            Err(SerializeBusinessObjectError::SizeMismatch { size: 4, payload_len: 5 }) => {},
+            other => panic!("Unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn try_to_bytes_should_reject_payload_without_size() {
+        let mut obj = BusinessObject::builder().event("foo/bar").payload("ABCDE").build();
+        obj.size = None;
+
+        match obj.try_to_bytes() {
+            Err(SerializeBusinessObjectError::PayloadWithoutSize(5)) => {},
+            other => panic!("Unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn try_to_bytes_should_reject_size_without_payload() {
+        let mut obj = BusinessObject::builder().event("foo/bar").build();
+        obj.size = Some(5);
+
+        let mut sink = Vec::new();
+        match obj.write_to(&mut sink) {
+            Err(SerializeBusinessObjectError::SizeWithoutPayload(5)) => {},
+            other => panic!("Unexpected result {:?}", other)
+        }
+        assert!(sink.is_empty());
+    }
+
+    #[test]
+    fn write_to_should_match_to_bytes() {
+        let obj = BusinessObject::builder().event("foo/bar").payload("ABCDE").build();
+
+        let mut sink = Vec::new();
+        let written = obj.write_to(&mut sink).unwrap();
+
+        assert_eq!(sink.len(), written);
+        assert_eq!(obj.to_bytes(), sink);
+    }
+
+    #[test]
+    fn shared_and_owned_payloads_should_compare_by_content() {
+        let owned = Payload::from("ABCDE");
+        let shared = owned.clone().into_shared();
+
+        match shared {
+            Payload::Shared(_) => {},
+            ref other => panic!("Expected a shared payload, got {:?}", other)
+        }
+        assert_eq!(owned, shared);
+        assert_eq!(b"ABCDE", shared.as_bytes());
+    }
+
+    #[test]
+    fn parse_type_should_split_off_charset() {
+        assert_eq!(("text/plain", None), parse_type("text/plain"));
+        assert_eq!(("text/plain", Some("utf-8")), parse_type("text/plain; charset=utf-8"));
+        assert_eq!(("text/plain", Some("ISO-8859-1")), parse_type("text/plain;format=flowed; Charset=\"ISO-8859-1\""));
+    }
+
+    #[test]
+    fn payload_text_should_honour_charset() {
+        let utf8 = BusinessObject::builder().payload_type("text/plain").payload("h\u{e4}h").build();
+        assert_eq!("h\u{e4}h", utf8.payload_text().unwrap());
+
+        let latin1 = BusinessObject::builder()
+            .payload_type("text/plain; charset=iso-8859-1")
+            .payload(vec![b'h', 0xe4, b'h'])
+            .build();
+        assert_eq!("h\u{e4}h", latin1.payload_text().unwrap());
+
+        let ascii = BusinessObject::builder()
+            .payload_type("text/plain; charset=us-ascii")
+            .payload(vec![b'h', 0xe4, b'h'])
+            .build();
+        match ascii.payload_text() {
+            Err(DecodePayloadError::InvalidCharacters { position: 1, .. }) => {},
+            other => panic!("Unexpected result {:?}", other)
+        }
+
+        let unknown = BusinessObject::builder().payload_type("text/plain; charset=klingon").payload("x").build();
+        match unknown.payload_text() {
+            Err(DecodePayloadError::UnsupportedCharset(ref charset)) if charset == "klingon" => {},
+            other => panic!("Unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn payload_json_should_parse_json_types() {
+        let obj = BusinessObject::builder().payload_type("application/json").payload(r#"{"a": [1, 2]}"#).build();
+        assert_eq!(Json::from_str(r#"{"a": [1, 2]}"#).unwrap(), obj.payload_json().unwrap());
+
+        let broken = BusinessObject::builder().payload_type("application/vnd.foo+json").payload("{").build();
+        match broken.payload_json() {
+            Err(DecodePayloadError::JsonSyntaxError(_)) => {},
+            other => panic!("Unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn typed_accessors_should_reject_mismatching_types() {
+        let image = BusinessObject::builder().payload_type("image/png").payload(vec![0x89, b'P']).build();
+
+        match image.payload_text() {
+            Err(DecodePayloadError::TypeMismatch { expected: "text", found: Some(ref found) }) if found == "image/png" => {},
+            other => panic!("Unexpected result {:?}", other)
+        }
+        match image.payload_json() {
+            Err(DecodePayloadError::TypeMismatch { expected: "JSON", .. }) => {},
+            other => panic!("Unexpected result {:?}", other)
+        }
+        assert_eq!(DecodedPayload::Bytes(&[0x89, b'P']), image.decoded_payload().unwrap());
+    }
+
+    #[test]
+    fn decoded_payload_should_follow_type() {
+        let text = BusinessObject::builder().payload_type("text/plain").payload("hello").build();
+        assert_eq!(DecodedPayload::Text("hello".to_string()), text.decoded_payload().unwrap());
+
+        let json = BusinessObject::builder().payload_type("application/json; charset=utf-8").payload("[1]").build();
+        assert_eq!(DecodedPayload::Json(Json::from_str("[1]").unwrap()), json.decoded_payload().unwrap());
+
+        let untyped = BusinessObject::builder().payload("hello").build();
+        assert_eq!(DecodedPayload::Bytes(b"hello"), untyped.decoded_payload().unwrap());
+    }
+
+    #[test]
+    fn bad_frame_should_keep_position_excerpt_and_cause() {
+        let header = vec![b'x'; HEADER_EXCERPT_LEN + 10];
+        let cause = Json::from_str("x").unwrap_err();
+        let error = ReadBusinessObjectError::JsonSyntaxError(cause).in_frame(1234, 3, &header);
+
+        match error {
+            ReadBusinessObjectError::BadFrame(ref bad) => {
+                assert_eq!(1234, bad.offset);
+                assert_eq!(3, bad.index);
+                assert_eq!(HEADER_EXCERPT_LEN, bad.header.len());
+                assert_eq!(HEADER_EXCERPT_LEN + 10, bad.header_len);
+            },
+            ref other => panic!("Unexpected error {:?}", other)
+        }
+
+        let message = format!("{}", error);
+        assert!(message.starts_with("Bad frame #3 at byte 1234: Invalid JSON in header: "), "{}", message);
+        assert!(message.contains(&format!("({} of {} bytes)", HEADER_EXCERPT_LEN, HEADER_EXCERPT_LEN + 10)));
+
+        let cause = error.source().unwrap();
+        assert!(cause.source().is_some());
+    }
+
+    #[test]
+    fn from_json_should_say_what_header_is_instead_of_object() {
+        for &(header, kind) in &[("[1, 2]", "an array"), ("\"foo\"", "a string"), ("42", "a number"),
+                                 ("true", "a boolean"), ("null", "null")] {
+            match BusinessObject::from_json(&Json::from_str(header).unwrap()) {
+                Err(error @ ReadBusinessObjectError::JsonSemanticsError(_)) =>
+                    assert_eq!(format!("Header is {}, not a JSON object", kind), error.to_string()),
+                other => panic!("Unexpected result {:?}", other)
+            }
+        }
     }
 }
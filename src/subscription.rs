@@ -1,5 +1,53 @@
+//! Subscriptions decide which objects a client of the broker gets. A
+//! subscription is a list of rules, applied in order: the last rule matching
+//! an object decides whether the object passes, and an object no rule
+//! matches doesn't pass. A rule starting with `!` keeps the objects it
+//! matches out.
+//!
+//! Rules match the hierarchical names of objects: `@PATTERN` their event,
+//! `#PATTERN` any of their natures, and a bare `PATTERN` their type, with
+//! any parameters such as `; charset=utf-8` left out. A bare `*` matches
+//! every object, with or without a type.
+//!
+//! A pattern is made of segments separated by `/`, each matching a segment
+//! of the name:
+//!
+//! - `foo` matches the segment `foo`.
+//! - `?` matches any one segment: `sensors/?/temperature` matches
+//!   `sensors/kitchen/temperature`.
+//! - A segment with `*` or `?` among other characters is a glob, where `*`
+//!   matches any run of characters and `?` any one character: `log-*`
+//!   matches `log-error` and `log-` but not `log`.
+//! - `*` matches the rest of the name, however many segments it has,
+//!   including none: `routing/*` matches `routing` and `routing/subscribe`.
+//!   Anything after it in the pattern is ignored, so `routing/*/reply`
+//!   matches just what `routing/*` does.
+//!
+//! A pattern matches every name below the ones it matches, too: `routing`
+//! matches `routing/subscribe` just as `routing/*` does.
+//!
+//! Rules starting with `$` test a top-level metadata key of the object:
+//!
+//! - `$KEY` matches objects with the key, whatever its value.
+//! - `$KEY=VALUE` matches a string equal to `VALUE`, or a number or boolean
+//!   written as `VALUE`: `$sender=sensor-gateway`, `$retries=3`.
+//! - `$KEY^=PREFIX` matches a string starting with `PREFIX`:
+//!   `$channel^=#ops`.
+//! - `$KEY>N`, `$KEY>=N`, `$KEY<N` and `$KEY<=N` match numbers compared to
+//!   `N`, and `$KEY=A..B` numbers from `A` to `B`, both included.
+//!
+//! The key ends at the first `=`, `^`, `>` or `<`, so keys containing those
+//! can't be tested.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hash;
+use std::ops::Bound;
+
 use rustc_serialize::json::{Json, ToJson};
 
+use ::object::{parse_type, BusinessObject};
+
 
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub enum BusinessSubscription {
@@ -11,6 +59,7 @@ pub enum BusinessSubscription {
 #[derive(Debug)]
 pub enum BusinessSubscriptionError {
     JsonTypeError(Json),
+    BadMetadataRule(String),
     NoSubscriptionMetadataKey,
     SubscriptionNotEvent,
     UnknownSubscriptionEvent,
@@ -38,8 +87,12 @@ impl ToJson for BusinessSubscription {
 
 
 pub fn parse_subscription(subscription: &Json) -> Result<BusinessSubscription, BusinessSubscriptionError> {
-    if subscription.is_string() {
-        Ok(BusinessSubscription::String(String::from(subscription.as_string().unwrap())))
+    if let Some(rule) = subscription.as_string() {
+        let unnegated = rule.strip_prefix('!').unwrap_or(rule);
+        if let Some(predicate) = unnegated.strip_prefix('$') {
+            MetadataPredicate::parse(predicate)?;
+        }
+        Ok(BusinessSubscription::String(String::from(rule)))
     } else if subscription.as_array().is_some() {
         let array = subscription.as_array().unwrap();
 
@@ -54,10 +107,9 @@ pub fn parse_subscription(subscription: &Json) -> Result<BusinessSubscription, B
             }
         }
 
-        if !error.is_some() {
-            Ok(BusinessSubscription::List(result))
-        } else {
-            Err(error.unwrap())
+        match error {
+            None => Ok(BusinessSubscription::List(result)),
+            Some(e) => Err(e)
         }
     } else {
         Err(BusinessSubscriptionError::JsonTypeError(subscription.to_json()))
@@ -65,136 +117,751 @@ pub fn parse_subscription(subscription: &Json) -> Result<BusinessSubscription, B
 }
 
 
-fn match_hierarchical(matcher: &str, matchable: &str) -> bool {
-    let matcher_parts: Vec<&str> = matcher.split('/').collect();
-    let matchable_parts: Vec<&str> = matchable.split('/').collect();
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// `?`, which matches any one segment.
+    Any,
+    Glob(String)
+}
 
-    for (index, matcher_part) in matcher_parts.iter().enumerate() {
-        if matcher_part == &"*" {
-            return true;
-        }
 
-        if index >= matchable_parts.len() {
-            return false;
+impl Segment {
+    fn matches(&self, part: &str) -> bool {
+        match *self {
+            Segment::Literal(ref literal) => literal == part,
+            Segment::Any => true,
+            Segment::Glob(ref glob) => glob_matches(glob, part)
         }
+    }
+}
+
+
+/// The segments of `pattern`. The `*` matching the rest of the name is left
+/// out, as a pattern matches the names below the ones it matches anyway.
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    // A `*` segment matches the rest of the name, wherever it stands, so
+    // whatever follows it is never looked at.
+    pattern.split('/')
+        .take_while(|&part| part != "*")
+        .map(|part| match part {
+            "?" => Segment::Any,
+            _ if part.contains(['*', '?']) => Segment::Glob(part.to_string()),
+            _ => Segment::Literal(part.to_string())
+        })
+        .collect()
+}
+
+
+/// Matches `text` against the in-segment `glob`, where `*` matches any run
+/// of characters and `?` any one.
+fn glob_matches(glob: &str, text: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut g, mut t) = (0, 0);
+    // Where to resume matching after the last `*`, should the rest fail
+    let mut retry: Option<(usize, usize)> = None;
 
-        let matchable_part = matchable_parts[index];
-        if matcher_part != &matchable_part {
+    while t < text.len() {
+        if g < glob.len() && glob[g] == '*' {
+            retry = Some((g + 1, t));
+            g += 1;
+        } else if g < glob.len() && (glob[g] == '?' || glob[g] == text[t]) {
+            g += 1;
+            t += 1;
+        } else if let Some((after_star, skipped)) = retry {
+            retry = Some((after_star, skipped + 1));
+            g = after_star;
+            t = skipped + 1;
+        } else {
             return false;
         }
     }
 
-    true
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+
+/// Whether `segments` match the name split into `parts`, or a name above it.
+fn match_segments(segments: &[Segment], parts: &[&str]) -> bool {
+    segments.len() <= parts.len() && segments.iter().zip(parts).all(|(segment, part)| segment.matches(part))
+}
+
+
+fn match_hierarchical(matcher: &str, matchable: &str) -> bool {
+    let parts: Vec<&str> = matchable.split('/').collect();
+    match_segments(&parse_pattern(matcher), &parts)
 }
 
 
 pub fn match_hierarchical_subscription(matcher: BusinessSubscription,
                                        matchable: BusinessSubscription) -> bool {
-    match (matcher, matchable) {
-        (BusinessSubscription::String(mr), BusinessSubscription::String(me)) => {
-            return match_hierarchical(&mr, &me);
-        }
-        (_, _) => {}
+    if let (BusinessSubscription::String(mr), BusinessSubscription::String(me)) = (matcher, matchable) {
+        return match_hierarchical(&mr, &me);
     }
 
     false
 }
 
 
-fn subscription_vec_to_str_vec<'a>(subscription_vec: &'a Vec<BusinessSubscription>) -> Option<Vec<&str>> {
-    let mut result: Vec<&str> = Vec::new();
+/// Rules over one kind of hierarchical name, their patterns split into
+/// segments in a trie. A pattern matches the names below the ones it
+/// matches, so the rules matching a name are those of the nodes its leading
+/// segments lead to.
+#[derive(Debug, Clone, Default)]
+struct RuleTrie<R> {
+    rules: R,
+    children: HashMap<String, RuleTrie<R>>,
+    any: Option<Box<RuleTrie<R>>>,
+    globs: Vec<(String, RuleTrie<R>)>
+}
 
-    for item in subscription_vec.iter() {
-        match *item {
-            BusinessSubscription::String(ref s) => {
-                result.push(s);
+
+impl<R: Default> RuleTrie<R> {
+    fn rules_mut(&mut self, segments: &[Segment]) -> &mut R {
+        let mut node = self;
+
+        for segment in segments {
+            node = match *segment {
+                Segment::Literal(ref literal) => node.children.entry(literal.clone()).or_default(),
+                Segment::Any => node.any.get_or_insert_with(Box::default),
+                Segment::Glob(ref glob) => {
+                    let index = match node.globs.iter().position(|(other, _)| other == glob) {
+                        Some(index) => index,
+                        None => {
+                            node.globs.push((glob.clone(), RuleTrie::default()));
+                            node.globs.len() - 1
+                        }
+                    };
+                    &mut node.globs[index].1
+                }
+            };
+        }
+
+        &mut node.rules
+    }
+
+    /// Calls `found` with the rules of every node the name split into
+    /// `parts` leads to.
+    fn visit<F: FnMut(&R)>(&self, parts: &[&str], found: &mut F) {
+        found(&self.rules);
+
+        if let Some((part, rest)) = parts.split_first() {
+            if let Some(child) = self.children.get(*part) {
+                child.visit(rest, found);
+            }
+            if let Some(ref any) = self.any {
+                any.visit(rest, found);
+            }
+            for (glob, child) in &self.globs {
+                if glob_matches(glob, part) {
+                    child.visit(rest, found);
+                }
+            }
+        }
+    }
+}
+
+
+impl<K: Eq + Hash> RuleTrie<HashMap<K, usize>> {
+    /// Removes the rule of `subscriber` at `segments`, pruning the nodes left
+    /// empty. Returns whether this node is left empty.
+    fn remove(&mut self, segments: &[Segment], subscriber: &K) -> bool {
+        match segments.split_first() {
+            None => {
+                self.rules.remove(subscriber);
+            },
+            Some((Segment::Literal(literal), rest)) => {
+                let prune = match self.children.get_mut(literal) {
+                    Some(child) => child.remove(rest, subscriber),
+                    None => false
+                };
+                if prune {
+                    self.children.remove(literal);
+                }
+            },
+            Some((Segment::Any, rest)) => {
+                let prune = match self.any {
+                    Some(ref mut any) => any.remove(rest, subscriber),
+                    None => false
+                };
+                if prune {
+                    self.any = None;
+                }
             },
-            _ => {
-                return None;
+            Some((Segment::Glob(glob), rest)) => {
+                if let Some(index) = self.globs.iter().position(|(other, _)| other == glob) {
+                    if self.globs[index].1.remove(rest, subscriber) {
+                        self.globs.remove(index);
+                    }
+                }
             }
         }
+
+        self.rules.is_empty() && self.children.is_empty() && self.any.is_none() && self.globs.is_empty()
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum MetadataTest {
+    Exists,
+    Equals(String),
+    StartsWith(String),
+    Range(Bound<f64>, Bound<f64>)
+}
+
+
+impl MetadataTest {
+    fn matches(&self, value: &Json) -> bool {
+        match *self {
+            MetadataTest::Exists => true,
+            MetadataTest::Equals(ref expected) => match *value {
+                Json::String(ref value) => value == expected,
+                Json::Boolean(value) => value.to_string() == *expected,
+                Json::I64(_) | Json::U64(_) | Json::F64(_) =>
+                    expected.parse::<f64>().ok() == value.as_f64(),
+                _ => false
+            },
+            MetadataTest::StartsWith(ref prefix) => match value.as_string() {
+                Some(value) => value.starts_with(prefix.as_str()),
+                None => false
+            },
+            MetadataTest::Range(ref min, ref max) => match value.as_f64() {
+                Some(value) => {
+                    let above_min = match *min {
+                        Bound::Included(min) => value >= min,
+                        Bound::Excluded(min) => value > min,
+                        Bound::Unbounded => true
+                    };
+                    let below_max = match *max {
+                        Bound::Included(max) => value <= max,
+                        Bound::Excluded(max) => value < max,
+                        Bound::Unbounded => true
+                    };
+                    above_min && below_max
+                },
+                None => false
+            }
+        }
+    }
+}
+
+
+/// A test of the metadata under `key`.
+#[derive(Debug, Clone, PartialEq)]
+struct MetadataPredicate {
+    key: String,
+    test: MetadataTest
+}
+
+
+impl MetadataPredicate {
+    /// Parses a metadata rule with the `$` left out.
+    fn parse(rule: &str) -> Result<MetadataPredicate, BusinessSubscriptionError> {
+        let bad_rule = || BusinessSubscriptionError::BadMetadataRule(format!("${}", rule));
+        let number = |value: &str| value.trim().parse::<f64>().map_err(|_| bad_rule());
+
+        let (key, test) = match rule.find(['=', '^', '>', '<']) {
+            Some(end) => (&rule[..end], &rule[end..]),
+            None => (rule, "")
+        };
+        if key.is_empty() {
+            return Err(bad_rule());
+        }
+
+        let test = if test.is_empty() {
+            MetadataTest::Exists
+        } else if let Some(prefix) = test.strip_prefix("^=") {
+            MetadataTest::StartsWith(prefix.to_string())
+        } else if let Some(min) = test.strip_prefix(">=") {
+            MetadataTest::Range(Bound::Included(number(min)?), Bound::Unbounded)
+        } else if let Some(min) = test.strip_prefix('>') {
+            MetadataTest::Range(Bound::Excluded(number(min)?), Bound::Unbounded)
+        } else if let Some(max) = test.strip_prefix("<=") {
+            MetadataTest::Range(Bound::Unbounded, Bound::Included(number(max)?))
+        } else if let Some(max) = test.strip_prefix('<') {
+            MetadataTest::Range(Bound::Unbounded, Bound::Excluded(number(max)?))
+        } else if let Some(value) = test.strip_prefix('=') {
+            // A value that isn't a range of numbers is taken as it is
+            let range = value.find("..").and_then(|dots| {
+                match (number(&value[..dots]), number(&value[dots + 2..])) {
+                    (Ok(min), Ok(max)) => Some(MetadataTest::Range(Bound::Included(min), Bound::Included(max))),
+                    _ => None
+                }
+            });
+            range.unwrap_or_else(|| MetadataTest::Equals(value.to_string()))
+        } else {
+            return Err(bad_rule());
+        };
+
+        Ok(MetadataPredicate { key: key.to_string(), test })
+    }
+
+    fn matches(&self, metadata: &BTreeMap<String, Json>) -> bool {
+        match metadata.get(&self.key) {
+            Some(value) => self.test.matches(value),
+            None => false
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum RuleKind {
+    Nature,
+    Event,
+    Type,
+    /// `*`, which matches objects with or without a type.
+    Everything,
+    Metadata(MetadataPredicate)
+}
+
+
+#[derive(Debug, Clone)]
+struct Rule {
+    kind: RuleKind,
+    segments: Vec<Segment>,
+    negative: bool
+}
+
+
+/// The rules of `subscription` in order, or `None` if it isn't a list of
+/// rules.
+fn parse_rules(subscription: &BusinessSubscription) -> Option<Vec<Rule>> {
+    let rules = match *subscription {
+        BusinessSubscription::List(ref rules) => rules,
+        _ => return None
+    };
+
+    let mut result = Vec::new();
+
+    for rule in rules {
+        let mut rule: &str = match *rule {
+            BusinessSubscription::String(ref rule) => rule,
+            _ => return None
+        };
+
+        let negative = rule.starts_with('!');
+        if negative {
+            rule = &rule[1..];
+        }
+
+        let (kind, pattern) = if let Some(predicate) = rule.strip_prefix('$') {
+            match MetadataPredicate::parse(predicate) {
+                Ok(predicate) => (RuleKind::Metadata(predicate), ""),
+                Err(e) => {
+                    warn!("Ignoring subscription rule: {:?}", e);
+                    continue;
+                }
+            }
+        } else if let Some(nature) = rule.strip_prefix('#') {
+            (RuleKind::Nature, nature)
+        } else if let Some(event) = rule.strip_prefix('@') {
+            (RuleKind::Event, event)
+        } else if rule == "*" {
+            (RuleKind::Everything, rule)
+        } else {
+            (RuleKind::Type, rule)
+        };
+
+        let segments = match kind {
+            RuleKind::Metadata(_) => Vec::new(),
+            _ => parse_pattern(pattern)
+        };
+
+        result.push(Rule { kind, segments, negative });
     }
 
     Some(result)
 }
 
 
-fn routing_decision_aux(natures: Option<Vec<&str>>, event: Option<&str>, payload_type: Option<&str>,
-                        subscription_rules: &Vec<BusinessSubscription>) -> bool {
-    let rules_opt = subscription_vec_to_str_vec(&subscription_rules);
-    if rules_opt.is_none() {
-        return false;
+impl Rule {
+    fn matches(&self, key: &RoutingKey) -> bool {
+        match self.kind {
+            RuleKind::Nature => key.natures.iter().any(|nature| match_segments(&self.segments, nature)),
+            RuleKind::Event => match key.event {
+                Some(ref event) => match_segments(&self.segments, event),
+                None => false
+            },
+            RuleKind::Type => match key.payload_type {
+                Some(ref payload_type) => match_segments(&self.segments, payload_type),
+                None => false
+            },
+            RuleKind::Everything => true,
+            RuleKind::Metadata(ref predicate) => match key.metadata {
+                Some(metadata) => predicate.matches(metadata),
+                None => false
+            }
+        }
+    }
+}
+
+
+/// Whether the object of `key` passes `rules`, going through them one by one.
+fn passes(rules: &[Rule], key: &RoutingKey) -> bool {
+    match rules.iter().rev().find(|rule| rule.matches(key)) {
+        Some(rule) => !rule.negative,
+        None => false
+    }
+}
+
+
+/// The names of an object that subscriptions are matched against, split up
+/// once so that the object can be matched against any number of
+/// subscriptions.
+#[derive(Debug, Clone)]
+pub struct RoutingKey<'a> {
+    natures: Vec<Vec<&'a str>>,
+    event: Option<Vec<&'a str>>,
+    payload_type: Option<Vec<&'a str>>,
+    metadata: Option<&'a BTreeMap<String, Json>>
+}
+
+
+impl<'a> RoutingKey<'a> {
+    pub fn new(natures: Option<Vec<&'a str>>, event: Option<&'a str>, payload_type: Option<&'a str>) -> RoutingKey<'a> {
+        let mut payload_type_aux = payload_type;
+
+        // Remove trailing extra qualifiers for type for matching purposes
+        if let Some(val) = payload_type {
+            if val.contains(';') {
+                let (media_type, _) = parse_type(val);
+                payload_type_aux = Some(media_type);
+                debug!("Removed trailing parts from type: {} => {}", val, media_type);
+            }
+        };
+
+        RoutingKey {
+            natures: natures.unwrap_or_default().into_iter().map(split_name).collect(),
+            event: event.map(split_name),
+            payload_type: payload_type_aux.map(split_name),
+            metadata: None
+        }
+    }
+
+    pub fn from_object(object: &'a BusinessObject) -> RoutingKey<'a> {
+        RoutingKey::new(Some(object.natures()),
+                        object.event.as_ref().map(|event| event.as_ref()),
+                        object._type.as_ref().map(|t| t.as_ref()))
+            .with_metadata(&object.metadata)
+    }
+
+    /// Sets the metadata `$` rules test.
+    pub fn with_metadata(mut self, metadata: &'a BTreeMap<String, Json>) -> RoutingKey<'a> {
+        self.metadata = Some(metadata);
+        self
     }
-    let rules = rules_opt.unwrap();
+}
+
+
+fn split_name(name: &str) -> Vec<&str> {
+    name.split('/').collect()
+}
+
+
+/// A subscription compiled for matching objects against. A subscription
+/// that isn't a list of rules matches nothing.
+#[derive(Debug, Clone, Default)]
+pub struct SubscriptionMatcher {
+    events: RuleTrie<Option<usize>>,
+    natures: RuleTrie<Option<usize>>,
+    types: RuleTrie<Option<usize>>,
+    everything: Option<usize>,
+    predicates: Vec<(MetadataPredicate, usize)>,
+    negative: Vec<bool>
+}
 
-    let mut pass = false;
 
-    for mut rule in rules {
-        let is_negative_rule = rule.starts_with("!");
-        if is_negative_rule {
-            rule = &rule[1..rule.len()];
+impl SubscriptionMatcher {
+    pub fn compile(subscription: &BusinessSubscription) -> SubscriptionMatcher {
+        let mut matcher = SubscriptionMatcher::default();
+
+        for (index, rule) in parse_rules(subscription).unwrap_or_default().into_iter().enumerate() {
+            matcher.negative.push(rule.negative);
+
+            let last = match rule.kind {
+                RuleKind::Nature => matcher.natures.rules_mut(&rule.segments),
+                RuleKind::Event => matcher.events.rules_mut(&rule.segments),
+                RuleKind::Type => matcher.types.rules_mut(&rule.segments),
+                RuleKind::Everything => &mut matcher.everything,
+                RuleKind::Metadata(predicate) => {
+                    matcher.predicates.push((predicate, index));
+                    continue;
+                }
+            };
+            *last = Some(index);
         }
 
-        if rule.starts_with("#") {
-            rule = &rule[1..rule.len()];
-            match natures {
-                Some(ref nature_list) => {
-                    for nature in nature_list {
-                        if match_hierarchical(rule, nature) {
-                            pass = ! is_negative_rule;
-                            break;
-                        }
+        matcher
+    }
+
+    pub fn matches(&self, key: &RoutingKey) -> bool {
+        let mut last = self.everything;
+        let mut found = |rule: &Option<usize>| last = cmp::max(last, *rule);
+
+        for nature in &key.natures {
+            self.natures.visit(nature, &mut found);
+        }
+        if let Some(ref event) = key.event {
+            self.events.visit(event, &mut found);
+        }
+        if let Some(ref payload_type) = key.payload_type {
+            self.types.visit(payload_type, &mut found);
+        }
+        if let Some(metadata) = key.metadata {
+            for &(ref predicate, index) in &self.predicates {
+                if predicate.matches(metadata) {
+                    found(&Some(index));
+                }
+            }
+        }
+
+        match last {
+            Some(index) => !self.negative[index],
+            None => false
+        }
+    }
+}
+
+
+/// The subscriptions of many subscribers, indexed to find the subscribers an
+/// object passes the subscription of without matching the object against
+/// every subscription. Subscribers are known by keys such as the tokens of a
+/// broker's clients.
+#[derive(Debug, Clone)]
+pub struct SubscriptionIndex<K: Eq + Hash> {
+    events: RuleTrie<HashMap<K, usize>>,
+    natures: RuleTrie<HashMap<K, usize>>,
+    types: RuleTrie<HashMap<K, usize>>,
+    everything: HashMap<K, usize>,
+    /// The metadata rules, by the key they test.
+    predicates: HashMap<String, Vec<(K, MetadataTest, usize)>>,
+    subscribers: HashMap<K, Vec<Rule>>
+}
+
+
+impl<K: Copy + Eq + Hash> Default for SubscriptionIndex<K> {
+    fn default() -> SubscriptionIndex<K> {
+        SubscriptionIndex::new()
+    }
+}
+
+
+impl<K: Copy + Eq + Hash> SubscriptionIndex<K> {
+    pub fn new() -> SubscriptionIndex<K> {
+        SubscriptionIndex {
+            events: RuleTrie::default(),
+            natures: RuleTrie::default(),
+            types: RuleTrie::default(),
+            everything: HashMap::new(),
+            predicates: HashMap::new(),
+            subscribers: HashMap::new()
+        }
+    }
+
+    /// Indexes the subscription of `subscriber`, replacing the one it had.
+    pub fn insert(&mut self, subscriber: K, subscription: &BusinessSubscription) {
+        self.remove(subscriber);
+
+        let rules = parse_rules(subscription).unwrap_or_default();
+        for (index, rule) in rules.iter().enumerate() {
+            let rules = match rule.kind {
+                RuleKind::Nature => self.natures.rules_mut(&rule.segments),
+                RuleKind::Event => self.events.rules_mut(&rule.segments),
+                RuleKind::Type => self.types.rules_mut(&rule.segments),
+                RuleKind::Everything => &mut self.everything,
+                RuleKind::Metadata(ref predicate) => {
+                    self.predicates.entry(predicate.key.clone()).or_default()
+                        .push((subscriber, predicate.test.clone(), index));
+                    continue;
+                }
+            };
+            rules.insert(subscriber, index);
+        }
+
+        self.subscribers.insert(subscriber, rules);
+    }
+
+    /// Removes the subscription of `subscriber`, if it has one.
+    pub fn remove(&mut self, subscriber: K) {
+        let rules = match self.subscribers.remove(&subscriber) {
+            Some(rules) => rules,
+            None => return
+        };
+
+        for rule in rules {
+            match rule.kind {
+                RuleKind::Nature => { self.natures.remove(&rule.segments, &subscriber); },
+                RuleKind::Event => { self.events.remove(&rule.segments, &subscriber); },
+                RuleKind::Type => { self.types.remove(&rule.segments, &subscriber); },
+                RuleKind::Everything => { self.everything.remove(&subscriber); },
+                RuleKind::Metadata(predicate) => {
+                    let emptied = match self.predicates.get_mut(&predicate.key) {
+                        Some(tests) => {
+                            tests.retain(|&(other, _, _)| other != subscriber);
+                            tests.is_empty()
+                        },
+                        None => false
+                    };
+                    if emptied {
+                        self.predicates.remove(&predicate.key);
                     }
-                },
-                None => {}
+                }
             }
-        } else if rule.starts_with("@") {
-            rule = &rule[1..rule.len()];
-            match event {
-                Some(event) => {
-                    if match_hierarchical(rule, event) {
-                        pass = ! is_negative_rule
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    /// Whether the object of `key` passes the subscription of `subscriber`,
+    /// going through its rules alone.
+    pub fn matches(&self, subscriber: K, key: &RoutingKey) -> bool {
+        match self.subscribers.get(&subscriber) {
+            Some(rules) => passes(rules, key),
+            None => false
+        }
+    }
+
+    /// The subscribers whose subscriptions the object of `key` passes.
+    pub fn lookup(&self, key: &RoutingKey) -> HashSet<K> {
+        // The last rule of each subscriber matching the object
+        let mut last = self.everything.clone();
+        let mut note = |subscriber: K, index: usize| {
+            let last = last.entry(subscriber).or_insert(index);
+            *last = cmp::max(*last, index);
+        };
+
+        if let Some(metadata) = key.metadata {
+            for (key, value) in metadata {
+                for &(subscriber, ref test, index) in self.predicates.get(key).into_iter().flatten() {
+                    if test.matches(value) {
+                        note(subscriber, index);
                     }
-                },
-                None => {}
+                }
+            }
+        }
+
+        let mut found = |rules: &HashMap<K, usize>| {
+            for (&subscriber, &index) in rules {
+                note(subscriber, index);
             }
-        } else if rule == "*" || match payload_type { Some(payload_type) => match_hierarchical(rule, payload_type),
-                                                      None => false } {
-            pass = ! is_negative_rule
+        };
+
+        for nature in &key.natures {
+            self.natures.visit(nature, &mut found);
+        }
+        if let Some(ref event) = key.event {
+            self.events.visit(event, &mut found);
+        }
+        if let Some(ref payload_type) = key.payload_type {
+            self.types.visit(payload_type, &mut found);
+        }
+        last.into_iter()
+            .filter(|&(subscriber, index)| !self.subscribers[&subscriber][index].negative)
+            .map(|(subscriber, _)| subscriber)
+            .collect()
+    }
+}
+
+
+/// Whether `pattern` matches the name split into `parts`, or a name above
+/// it. Unlike `parse_pattern`, this allocates nothing.
+fn match_pattern(pattern: &str, parts: &[&str]) -> bool {
+    let mut parts = parts.iter();
+
+    for segment in pattern.split('/') {
+        if segment == "*" {
+            return true;
+        }
+
+        let part = match parts.next() {
+            Some(part) => part,
+            None => return false
+        };
+        let matches = match segment {
+            "?" => true,
+            _ if segment.contains(['*', '?']) => glob_matches(segment, part),
+            _ => segment == *part
+        };
+        if !matches {
+            return false;
         }
     }
 
-    pass
+    true
+}
+
+
+/// Whether `rule`, with any `!` left out, matches the object of `key`. A bad
+/// metadata rule matches nothing.
+fn rule_matches(rule: &str, key: &RoutingKey) -> bool {
+    if let Some(predicate) = rule.strip_prefix('$') {
+        match (MetadataPredicate::parse(predicate), key.metadata) {
+            (Ok(predicate), Some(metadata)) => predicate.matches(metadata),
+            _ => false
+        }
+    } else if let Some(nature) = rule.strip_prefix('#') {
+        key.natures.iter().any(|parts| match_pattern(nature, parts))
+    } else if let Some(event) = rule.strip_prefix('@') {
+        match key.event {
+            Some(ref parts) => match_pattern(event, parts),
+            None => false
+        }
+    } else if rule == "*" {
+        true
+    } else {
+        match key.payload_type {
+            Some(ref parts) => match_pattern(rule, parts),
+            None => false
+        }
+    }
 }
 
 
+/// Decides whether an object passes `subscription`, going through its rules
+/// as they are, last first. Routing many objects is cheaper through a
+/// `SubscriptionMatcher` compiled once.
 pub fn routing_decision(natures: Option<Vec<&str>>, event: Option<&str>, payload_type: Option<&str>,
                         subscription: &BusinessSubscription) -> bool {
-    let mut payload_type_aux = payload_type;
-
-    // Remove trailing extra qualifiers for type for matching purposes
-    match payload_type {
-        Some(val) => {
-            if val.contains(";") {
-                let parts: Vec<&str> = val.split(";").collect();
-                payload_type_aux = Some(parts[0].trim());
-                debug!("Removed trailing parts from type: {} => {}", val, payload_type_aux.unwrap());
-            }
-        },
-        None => {}
+    let rules = match *subscription {
+        BusinessSubscription::List(ref rules) => rules,
+        _ => return false
     };
+    if rules.iter().any(|rule| !matches!(*rule, BusinessSubscription::String(_))) {
+        return false;
+    }
 
-    match subscription {
-        &BusinessSubscription::List(ref rule_list) =>
-            routing_decision_aux(natures, event, payload_type_aux, rule_list),
-        _ => { false }
+    let key = RoutingKey::new(natures, event, payload_type);
+    for rule in rules.iter().rev() {
+        if let BusinessSubscription::String(ref rule) = *rule {
+            let unnegated = rule.strip_prefix('!');
+            if rule_matches(unnegated.unwrap_or(rule), &key) {
+                return unnegated.is_none();
+            }
+        }
     }
+
+    false
 }
 
 
 #[cfg(test)]
 mod tests {
-    use super::{BusinessSubscription, match_hierarchical_subscription, routing_decision};
+    use std::collections::HashSet;
+
+    use rustc_serialize::json::Json;
+
+    use ::object::BusinessObject;
+    use super::{BusinessSubscription, RoutingKey, SubscriptionIndex, SubscriptionMatcher,
+                match_hierarchical_subscription, parse_subscription, routing_decision};
 
     fn bs(bs: &str) -> BusinessSubscription {
         BusinessSubscription::String(bs.to_string())
@@ -207,7 +874,7 @@ mod tests {
     #[test]
     fn match_hierarchical_equal() {
         assert!(match_hierarchical_subscription(bs("routing/subscribe"),
-                                                bs("routing/subscribe")) == true);
+                                                bs("routing/subscribe")));
     }
 
     #[test]
@@ -232,6 +899,50 @@ mod tests {
                                                 bs("")));
     }
 
+    #[test]
+    fn match_hierarchical_should_keep_prefixes_matching_names_below() {
+        assert!(match_hierarchical_subscription(bs("routing"), bs("routing/subscribe")));
+        assert!(match_hierarchical_subscription(bs("routing/*"), bs("routing")));
+        assert!(!match_hierarchical_subscription(bs("routing/subscribe"), bs("routing")));
+    }
+
+    #[test]
+    fn match_hierarchical_single_segment_wildcards() {
+        assert!(match_hierarchical_subscription(bs("sensors/?/temperature"), bs("sensors/kitchen/temperature")));
+        assert!(!match_hierarchical_subscription(bs("sensors/?/temperature"), bs("sensors/kitchen/humidity")));
+        assert!(!match_hierarchical_subscription(bs("sensors/?/temperature"), bs("sensors/temperature")));
+        assert!(!match_hierarchical_subscription(bs("sensors/?"), bs("sensors")));
+        assert!(match_hierarchical_subscription(bs("?/*"), bs("sensors/kitchen/temperature")));
+    }
+
+    #[test]
+    fn match_hierarchical_star_should_match_everything_below_wherever_it_stands() {
+        assert!(match_hierarchical_subscription(bs("a/*"), bs("a")));
+        assert!(match_hierarchical_subscription(bs("a/*"), bs("a/x/y")));
+        assert!(match_hierarchical_subscription(bs("foo/*/bar"), bs("foo")));
+        assert!(match_hierarchical_subscription(bs("foo/*/bar"), bs("foo/x")));
+        assert!(match_hierarchical_subscription(bs("foo/*/bar"), bs("foo/x/y")));
+        assert!(match_hierarchical_subscription(bs("a/*/*"), bs("a")));
+        assert!(!match_hierarchical_subscription(bs("foo/*/bar"), bs("bar")));
+
+        let key = RoutingKey::new(None, Some("foo/x/y"), None);
+        let subscription = bs_list(vec!(bs("@foo/*/bar")));
+        assert!(SubscriptionMatcher::compile(&subscription).matches(&key));
+        assert!(routing_decision(None, Some("foo/x/y"), None, &subscription));
+    }
+
+    #[test]
+    fn match_hierarchical_globs_within_segment() {
+        assert!(match_hierarchical_subscription(bs("log-*"), bs("log-error")));
+        assert!(match_hierarchical_subscription(bs("log-*"), bs("log-")));
+        assert!(!match_hierarchical_subscription(bs("log-*"), bs("log")));
+        assert!(match_hierarchical_subscription(bs("app/*-v?/start"), bs("app/billing-v2/start")));
+        assert!(!match_hierarchical_subscription(bs("app/*-v?/start"), bs("app/billing-v12/start")));
+        assert!(match_hierarchical_subscription(bs("*a*b"), bs("xaybab")));
+        assert!(match_hierarchical_subscription(bs("t?st"), bs("täst")));
+        assert!(!match_hierarchical_subscription(bs("log-*"), bs("debug/log-error")));
+    }
+
     #[test]
     fn routing_decision_should_work_with_events() {
         assert!(routing_decision(None,
@@ -279,4 +990,264 @@ mod tests {
                                  Some("text/plain"),
                                  &bs_list(vec!(bs("!text/*")))));
     }
+
+    #[test]
+    fn matcher_should_let_last_matching_rule_decide() {
+        let matcher = SubscriptionMatcher::compile(&bs_list(vec!(bs("*"),
+                                                                 bs("!@routing/*"),
+                                                                 bs("@routing/announcement"))));
+
+        assert!(matcher.matches(&RoutingKey::new(None, Some("services/discovery"), None)));
+        assert!(!matcher.matches(&RoutingKey::new(None, Some("routing/stats"), None)));
+        assert!(matcher.matches(&RoutingKey::new(None, Some("routing/announcement"), None)));
+    }
+
+    #[test]
+    fn matcher_should_apply_negation_in_order_across_kinds() {
+        let natures = || Some(vec!("hasselhoff"));
+
+        let nature_first = SubscriptionMatcher::compile(&bs_list(vec!(bs("#hasselhoff"), bs("!@foo/*"))));
+        assert!(!nature_first.matches(&RoutingKey::new(natures(), Some("foo/bar"), None)));
+        assert!(nature_first.matches(&RoutingKey::new(natures(), Some("bar/foo"), None)));
+
+        let event_first = SubscriptionMatcher::compile(&bs_list(vec!(bs("!@foo/*"), bs("#hasselhoff"))));
+        assert!(event_first.matches(&RoutingKey::new(natures(), Some("foo/bar"), None)));
+        assert!(!event_first.matches(&RoutingKey::new(None, Some("foo/bar"), None)));
+    }
+
+    #[test]
+    fn matcher_should_match_types_without_parameters() {
+        let matcher = SubscriptionMatcher::compile(&bs_list(vec!(bs("text/plain"))));
+
+        assert!(matcher.matches(&RoutingKey::new(None, None, Some("text/plain; charset=utf-8"))));
+        assert!(!matcher.matches(&RoutingKey::new(None, None, Some("text/html"))));
+        assert!(!matcher.matches(&RoutingKey::new(None, None, None)));
+    }
+
+    #[test]
+    fn matcher_should_match_nothing_for_nested_or_bare_rules() {
+        let key = RoutingKey::new(Some(vec!("hasselhoff")), Some("foo/bar"), Some("text/plain"));
+
+        assert!(!SubscriptionMatcher::compile(&bs("*")).matches(&key));
+        assert!(!SubscriptionMatcher::compile(&bs_list(vec!(bs("*"), bs_list(vec!(bs("@foo/*")))))).matches(&key));
+        assert!(!SubscriptionMatcher::compile(&bs_list(Vec::new())).matches(&key));
+    }
+
+    #[test]
+    fn index_should_find_subscribers_like_matchers_do() {
+        let subscriptions = [bs_list(vec!(bs("*"), bs("!@routing/*"))),
+                             bs_list(vec!(bs("@routing/*"), bs("!@routing/stats"))),
+                             bs_list(vec!(bs("#hasselhoff"), bs("text/*"))),
+                             bs_list(vec!(bs("!#hasselhoff"), bs("@foo"))),
+                             bs("*")];
+        let keys = [RoutingKey::new(None, Some("routing/announcement"), None),
+                    RoutingKey::new(None, Some("routing/stats"), None),
+                    RoutingKey::new(Some(vec!("hasselhoff")), Some("foo/bar"), None),
+                    RoutingKey::new(None, Some("foo/bar"), Some("text/plain")),
+                    RoutingKey::new(None, None, None)];
+
+        let mut index = SubscriptionIndex::new();
+        for (subscriber, subscription) in subscriptions.iter().enumerate() {
+            index.insert(subscriber, subscription);
+        }
+
+        for key in &keys {
+            let expected: HashSet<usize> = subscriptions.iter().enumerate()
+                .filter(|&(_, subscription)| SubscriptionMatcher::compile(subscription).matches(key))
+                .map(|(subscriber, _)| subscriber)
+                .collect();
+            assert_eq!(expected, index.lookup(key), "{:?}", key);
+            for subscriber in 0 .. subscriptions.len() {
+                assert_eq!(expected.contains(&subscriber), index.matches(subscriber, key), "{:?}", key);
+            }
+        }
+    }
+
+    #[test]
+    fn index_should_replace_and_remove_subscriptions() {
+        let mut index = SubscriptionIndex::new();
+        let foo = RoutingKey::new(None, Some("foo/bar"), None);
+        let bar = RoutingKey::new(None, Some("bar/foo"), None);
+
+        index.insert("a", &bs_list(vec!(bs("@foo/*"))));
+        index.insert("b", &bs_list(vec!(bs("@foo"), bs("@bar"))));
+        assert_eq!(vec!("a", "b").into_iter().collect::<HashSet<_>>(), index.lookup(&foo));
+
+        index.insert("a", &bs_list(vec!(bs("@bar/*"))));
+        assert_eq!(vec!("b").into_iter().collect::<HashSet<_>>(), index.lookup(&foo));
+        assert_eq!(vec!("a", "b").into_iter().collect::<HashSet<_>>(), index.lookup(&bar));
+
+        index.remove("a");
+        index.remove("b");
+        index.remove("c");
+        assert!(index.lookup(&foo).is_empty());
+        assert!(index.is_empty());
+        assert!(index.events.children.is_empty());
+    }
+
+    #[test]
+    fn matcher_should_match_wildcards_for_every_kind() {
+        let matcher = SubscriptionMatcher::compile(&bs_list(vec!(bs("@sensors/?/temperature"),
+                                                                 bs("!@sensors/attic/*"),
+                                                                 bs("#log-*"),
+                                                                 bs("image/?"))));
+
+        assert!(matcher.matches(&RoutingKey::new(None, Some("sensors/kitchen/temperature"), None)));
+        assert!(!matcher.matches(&RoutingKey::new(None, Some("sensors/attic/temperature"), None)));
+        assert!(!matcher.matches(&RoutingKey::new(None, Some("sensors/kitchen/humidity"), None)));
+        assert!(matcher.matches(&RoutingKey::new(Some(vec!("debug", "log-error")), None, None)));
+        assert!(matcher.matches(&RoutingKey::new(None, None, Some("image/png"))));
+        assert!(!matcher.matches(&RoutingKey::new(None, None, Some("image"))));
+    }
+
+    #[test]
+    fn index_should_find_subscribers_by_wildcards() {
+        let subscriptions = [bs_list(vec!(bs("@sensors/?/temperature"))),
+                             bs_list(vec!(bs("@sensors/?"), bs("!@sensors/kitchen/*"))),
+                             bs_list(vec!(bs("@sensors/k*"))),
+                             bs_list(vec!(bs("@*"), bs("!@*/?/temperature")))];
+        let keys = [RoutingKey::new(None, Some("sensors/kitchen/temperature"), None),
+                    RoutingKey::new(None, Some("sensors/attic/temperature"), None),
+                    RoutingKey::new(None, Some("sensors/kitchen"), None),
+                    RoutingKey::new(None, Some("sensors"), None)];
+
+        let mut index = SubscriptionIndex::new();
+        for (subscriber, subscription) in subscriptions.iter().enumerate() {
+            index.insert(subscriber, subscription);
+        }
+
+        for key in &keys {
+            let expected: HashSet<usize> = subscriptions.iter().enumerate()
+                .filter(|&(_, subscription)| SubscriptionMatcher::compile(subscription).matches(key))
+                .map(|(subscriber, _)| subscriber)
+                .collect();
+            assert_eq!(expected, index.lookup(key), "{:?}", key);
+            for subscriber in 0 .. subscriptions.len() {
+                assert_eq!(expected.contains(&subscriber), index.matches(subscriber, key), "{:?}", key);
+            }
+        }
+        assert_eq!(vec!(0, 2).into_iter().collect::<HashSet<_>>(), index.lookup(&keys[0]));
+
+        for subscriber in 0..subscriptions.len() {
+            index.remove(subscriber);
+        }
+        assert!(index.events.children.is_empty());
+    }
+
+    fn gateway_reading(temperature: f64) -> BusinessObject {
+        BusinessObject::builder()
+            .event("sensors/kitchen/temperature")
+            .metadata("sender", "sensor-gateway")
+            .metadata("channel", "#ops-alerts")
+            .metadata("temperature", &temperature)
+            .metadata("calibrated", &true)
+            .build()
+    }
+
+    fn passes(rules: Vec<&str>, object: &BusinessObject) -> bool {
+        let subscription = bs_list(rules.into_iter().map(bs).collect());
+        SubscriptionMatcher::compile(&subscription).matches(&RoutingKey::from_object(object))
+    }
+
+    #[test]
+    fn matcher_should_test_metadata() {
+        let object = gateway_reading(21.5);
+
+        assert!(passes(vec!("$sender"), &object));
+        assert!(!passes(vec!("$receiver"), &object));
+        assert!(passes(vec!("$sender=sensor-gateway"), &object));
+        assert!(!passes(vec!("$sender=sensor"), &object));
+        assert!(passes(vec!("$channel^=#ops"), &object));
+        assert!(!passes(vec!("$sender^=#ops"), &object));
+        assert!(passes(vec!("$calibrated=true"), &object));
+        assert!(passes(vec!("$temperature=21.5"), &object));
+        assert!(passes(vec!("$temperature>21"), &object));
+        assert!(passes(vec!("$temperature>=21.5"), &object));
+        assert!(!passes(vec!("$temperature<21.5"), &object));
+        assert!(passes(vec!("$temperature<=21.5"), &object));
+        assert!(passes(vec!("$temperature=20..25"), &object));
+        assert!(!passes(vec!("$temperature=22..25"), &object));
+        assert!(!passes(vec!("$sender>1"), &object));
+    }
+
+    #[test]
+    fn matcher_should_order_metadata_rules_with_the_rest() {
+        assert!(!passes(vec!("@sensors/*", "!$sender=sensor-gateway"), &gateway_reading(21.5)));
+        assert!(passes(vec!("!$sender=sensor-gateway", "@sensors/*"), &gateway_reading(21.5)));
+        assert!(passes(vec!("@sensors/*", "!$temperature>30", "$calibrated"), &gateway_reading(40.0)));
+        assert!(!passes(vec!("@sensors/*", "!$temperature>30"), &gateway_reading(40.0)));
+        assert!(!SubscriptionMatcher::compile(&bs_list(vec!(bs("$sender"))))
+                .matches(&RoutingKey::new(None, Some("sensors/kitchen/temperature"), None)));
+    }
+
+    #[test]
+    fn index_should_find_subscribers_by_metadata() {
+        let subscriptions = [bs_list(vec!(bs("$sender=sensor-gateway"))),
+                             bs_list(vec!(bs("*"), bs("!$temperature>30"))),
+                             bs_list(vec!(bs("$channel^=#ops"), bs("!@sensors/*"))),
+                             bs_list(vec!(bs("$temperature=0..25")))];
+        let objects = [gateway_reading(21.5), gateway_reading(40.0), BusinessObject::builder().build()];
+
+        let mut index = SubscriptionIndex::new();
+        for (subscriber, subscription) in subscriptions.iter().enumerate() {
+            index.insert(subscriber, subscription);
+        }
+
+        for object in &objects {
+            let key = RoutingKey::from_object(object);
+            let expected: HashSet<usize> = subscriptions.iter().enumerate()
+                .filter(|&(_, subscription)| SubscriptionMatcher::compile(subscription).matches(&key))
+                .map(|(subscriber, _)| subscriber)
+                .collect();
+            assert_eq!(expected, index.lookup(&key), "{:?}", object);
+            for subscriber in 0 .. subscriptions.len() {
+                assert_eq!(expected.contains(&subscriber), index.matches(subscriber, &key), "{:?}", object);
+            }
+        }
+        assert_eq!(vec!(0, 1, 3).into_iter().collect::<HashSet<_>>(),
+                   index.lookup(&RoutingKey::from_object(&objects[0])));
+
+        for subscriber in 0..subscriptions.len() {
+            index.remove(subscriber);
+        }
+        assert!(index.predicates.is_empty());
+    }
+
+    #[test]
+    fn parse_subscription_should_reject_bad_metadata_rules() {
+        let parse = |rule: &str| parse_subscription(&Json::Array(vec!(Json::String(rule.to_string()))));
+
+        assert!(parse("$sender=sensor-gateway").is_ok());
+        assert!(parse("!$channel^=#ops").is_ok());
+        assert!(parse("$version=1..2..3").is_ok());
+        assert!(parse("$").is_err());
+        assert!(parse("$=value").is_err());
+        assert!(parse("!$temperature>hot").is_err());
+        assert!(parse("$temperature<=").is_err());
+        assert!(parse("$sender^sensor").is_err());
+    }
+
+    #[test]
+    fn routing_decision_should_agree_with_compiled_matcher() {
+        let subscriptions = [bs_list(vec!(bs("*"), bs("!@routing/*"), bs("@routing/announcement"))),
+                             bs_list(vec!(bs("@sensors/?/temperature"), bs("!#log-*"))),
+                             bs_list(vec!(bs("text/*"), bs("!text/html"), bs("#?/debug"))),
+                             bs_list(vec!(bs("@foo"), bs_list(Vec::new()))),
+                             bs("*")];
+        let objects = [
+            (None, Some("routing/announcement"), None),
+            (None, Some("routing/stats"), None),
+            (Some(vec!("log-error")), Some("sensors/kitchen/temperature"), None),
+            (Some(vec!("app/debug")), Some("foo/bar"), Some("text/html; charset=utf-8")),
+            (None, None, Some("text/plain"))];
+
+        for subscription in &subscriptions {
+            let matcher = SubscriptionMatcher::compile(subscription);
+            for &(ref natures, event, payload_type) in &objects {
+                assert_eq!(matcher.matches(&RoutingKey::new(natures.clone(), event, payload_type)),
+                           routing_decision(natures.clone(), event, payload_type, subscription),
+                           "{:?} {:?}", subscription, event);
+            }
+        }
+    }
 }